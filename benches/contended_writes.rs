@@ -0,0 +1,55 @@
+//! Write throughput of the single-lock map vs the sharded one, each thread subscribes and
+//! unsubscribes its own client
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::thread;
+use submap::{ConcurrentSubMap, ShardedSubMap, SubMap};
+
+const THREADS: u32 = 8;
+const OPS: u32 = 1_000;
+
+fn run_writers<F>(f: F)
+where
+    F: Fn(u32, &str) + Sync,
+{
+    thread::scope(|s| {
+        for client in 0..THREADS {
+            let f = &f;
+            s.spawn(move || {
+                for i in 0..OPS {
+                    f(client, &format!("unit/{}/{}", client, i % 10));
+                }
+            });
+        }
+    });
+}
+
+fn contended_writes(c: &mut Criterion) {
+    let template = SubMap::new().match_any("+").wildcard("#");
+    let single: ConcurrentSubMap<u32> = template.clone().into();
+    let sharded: ShardedSubMap<u32> = ShardedSubMap::with_template(&template, THREADS as usize);
+    for client in 0..THREADS {
+        single.register_client(&client);
+        sharded.register_client(&client);
+    }
+    let mut group = c.benchmark_group("contended_writes");
+    group.bench_function("single_lock", |b| {
+        b.iter(|| {
+            run_writers(|client, topic| {
+                single.subscribe(topic, &client);
+                single.unsubscribe(topic, &client);
+            });
+        });
+    });
+    group.bench_function("sharded", |b| {
+        b.iter(|| {
+            run_writers(|client, topic| {
+                sharded.subscribe(topic, &client);
+                sharded.unsubscribe(topic, &client);
+            });
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, contended_writes);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+//! Maps keyed by digests, run with different engines to compare hashers, e.g.:
+//!
+//! cargo bench --bench digest_clients --features digest,indexmap
+//! cargo bench --bench digest_clients --features digest,indexmap,fxhash
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use submap::digest::{sha256, Sha256Digest};
+use submap::SubMap;
+
+const CLIENTS: usize = 10_000;
+
+fn digest_map() -> (SubMap<Sha256Digest>, Vec<Sha256Digest>) {
+    let mut smap = SubMap::new().match_any("+").wildcard("#");
+    let clients: Vec<Sha256Digest> = (0..CLIENTS)
+        .map(|i| sha256(format!("client{}", i)))
+        .collect();
+    for (i, client) in clients.iter().enumerate() {
+        smap.register_client(client);
+        smap.subscribe(&format!("unit/{}/+", i % 100), client);
+        smap.subscribe("unit/#", client);
+    }
+    (smap, clients)
+}
+
+fn digest_clients(c: &mut Criterion) {
+    let (mut smap, clients) = digest_map();
+    let mut group = c.benchmark_group("digest_clients");
+    group.bench_function("get_subscribers", |b| {
+        b.iter(|| smap.get_subscribers(black_box("unit/5/temp")));
+    });
+    group.bench_function("has_subscription", |b| {
+        b.iter(|| {
+            clients
+                .iter()
+                .filter(|client| smap.has_subscription(client, black_box("unit/#")))
+                .count()
+        });
+    });
+    group.bench_function("resubscribe", |b| {
+        b.iter(|| {
+            for client in &clients[..100] {
+                smap.unsubscribe("unit/#", client);
+                smap.subscribe("unit/#", client);
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, digest_clients);
+criterion_main!(benches);
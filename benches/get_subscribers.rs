@@ -0,0 +1,41 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use submap::types::Set;
+use submap::SubMap;
+
+const CLIENTS: u32 = 10_000;
+
+/// Collecting large wildcard subscriber sets: the lookup extends the result with cloned
+/// elements, the "clone_set" case shows the previous approach, which cloned the whole set first
+fn extend(c: &mut Criterion) {
+    let subscribers: Set<u32> = (0..CLIENTS).collect();
+    let mut group = c.benchmark_group("extend");
+    group.bench_function("clone_set", |b| {
+        b.iter_batched_ref(
+            Set::<u32>::default,
+            |result| result.extend(black_box(&subscribers).clone()),
+            BatchSize::SmallInput,
+        );
+    });
+    group.bench_function("iter_cloned", |b| {
+        b.iter_batched_ref(
+            Set::<u32>::default,
+            |result| result.extend(black_box(&subscribers).iter().copied()),
+            BatchSize::SmallInput,
+        );
+    });
+    group.finish();
+}
+
+fn wildcard_subscribers(c: &mut Criterion) {
+    let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+    for client in 0..CLIENTS {
+        smap.register_client(&client);
+        smap.subscribe(if client % 2 == 0 { "unit/#" } else { "+/temp" }, &client);
+    }
+    c.bench_function("get_subscribers_wildcard", |b| {
+        b.iter(|| smap.get_subscribers(black_box("unit/temp")));
+    });
+}
+
+criterion_group!(benches, extend, wildcard_subscribers);
+criterion_main!(benches);
@@ -1,8 +1,10 @@
 use crate::submap::SubMap;
+use crate::Error;
 
 #[derive(Debug, Default, Clone)]
 pub struct AclMap {
     smap: SubMap<()>,
+    deny: SubMap<()>,
 }
 
 impl AclMap {
@@ -10,61 +12,265 @@ impl AclMap {
     pub fn new() -> Self {
         let mut acl_map = Self::default();
         acl_map.smap.register_client(&());
+        acl_map.deny.register_client(&());
         acl_map
     }
+    /// Creates an ACL with the default tokens, which allows the given topics
+    #[inline]
+    pub fn from_topics(topics: &[&str]) -> Self {
+        topics.iter().collect()
+    }
     #[inline]
     pub fn separator(mut self, separator: char) -> Self {
         self.smap = self.smap.separator(separator);
+        self.deny = self.deny.separator(separator);
+        self
+    }
+    #[inline]
+    pub fn separator_str(mut self, separator: &str) -> Self {
+        self.smap = self.smap.separator_str(separator);
+        self.deny = self.deny.separator_str(separator);
         self
     }
     #[inline]
     pub fn wildcard(mut self, wildcard: &str) -> Self {
         self.smap = self.smap.wildcard(wildcard);
+        self.deny = self.deny.wildcard(wildcard);
         self
     }
     #[inline]
     pub fn match_any(mut self, match_any: &str) -> Self {
         self.smap = self.smap.match_any(match_any);
+        self.deny = self.deny.match_any(match_any);
         self
     }
     #[inline]
     pub fn formula_prefix(mut self, formula_prefix: &str) -> Self {
         self.smap = self.smap.formula_prefix(formula_prefix);
+        self.deny = self.deny.formula_prefix(formula_prefix);
         self
     }
     #[inline]
     pub fn regex_prefix(mut self, regex_prefix: &str) -> Self {
         self.smap = self.smap.regex_prefix(regex_prefix);
+        self.deny = self.deny.regex_prefix(regex_prefix);
         self
     }
     #[inline]
     pub fn wildcard_multiple(mut self, wildcard_multiple: &[&str]) -> Self {
         self.smap = self.smap.wildcard_multiple(wildcard_multiple);
+        self.deny = self.deny.wildcard_multiple(wildcard_multiple);
         self
     }
     #[inline]
     pub fn match_any_multiple(mut self, match_any_multiple: &[&str]) -> Self {
         self.smap = self.smap.match_any_multiple(match_any_multiple);
+        self.deny = self.deny.match_any_multiple(match_any_multiple);
         self
     }
     #[inline]
     pub fn insert(&mut self, topic: &str) {
         self.smap.subscribe(topic, &());
     }
+    /// Removes the topic rule, returns false if the rule did not exist
     #[inline]
-    pub fn remove(&mut self, topic: &str) {
-        self.smap.unsubscribe(topic, &());
+    pub fn remove(&mut self, topic: &str) -> bool {
+        if self.smap.has_subscription(&(), topic) {
+            self.smap.unsubscribe(topic, &());
+            true
+        } else {
+            false
+        }
     }
+    /// Adds a deny rule, deny rules have precedence over allow ones
+    #[inline]
+    pub fn deny(&mut self, topic: &str) {
+        self.deny.subscribe(topic, &());
+    }
+    /// Removes the deny rule, returns false if the rule did not exist
+    #[inline]
+    pub fn remove_deny(&mut self, topic: &str) -> bool {
+        if self.deny.has_subscription(&(), topic) {
+            self.deny.unsubscribe(topic, &());
+            true
+        } else {
+            false
+        }
+    }
+    /// Merges allow and deny rules of another ACL into the current one
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ConfigConflict`] if the ACLs have different token or separator
+    /// configurations, the current ACL is not modified in this case
+    pub fn merge(&mut self, other: &AclMap) -> Result<(), Error> {
+        // allow and deny maps always share the same configuration
+        self.smap.merge(other.smap.clone())?;
+        self.deny.merge(other.deny.clone())
+    }
+    /// Returns true if the topic matches an allow rule and does not match any deny rule
     #[inline]
     pub fn matches(&self, topic: &str) -> bool {
-        self.smap.is_subscribed(topic)
+        self.smap.is_subscribed(topic) && !self.deny.is_subscribed(topic)
+    }
+    /// Same as [`AclMap::matches`] but returns the allow rule pattern the topic matched, if there
+    /// are multiple matching rules, any of them is returned
+    pub fn matches_with_reason(&self, topic: &str) -> Option<String> {
+        if self.deny.is_subscribed(topic) {
+            return None;
+        }
+        self.smap
+            .matching_patterns(topic)
+            .into_iter()
+            .next()
+            .map(|((), pattern)| pattern)
+    }
+    /// Returns true if any of the topics matches the ACL (false for an empty slice)
+    #[inline]
+    pub fn matches_any(&self, topics: &[&str]) -> bool {
+        topics.iter().any(|topic| self.matches(topic))
+    }
+    /// Returns true if all the topics match the ACL (true for an empty slice)
+    #[inline]
+    pub fn matches_all(&self, topics: &[&str]) -> bool {
+        topics.iter().all(|topic| self.matches(topic))
+    }
+    /// Checks if the exact topic pattern is stored in the ACL (unlike "matches", the topic is not
+    /// matched against patterns)
+    #[inline]
+    pub fn contains(&self, topic: &str) -> bool {
+        self.smap.has_subscription(&(), topic)
     }
     #[inline]
     pub fn list(&self) -> Vec<&str> {
         self.smap.list_topics(&())
     }
     #[inline]
+    pub fn list_deny(&self) -> Vec<&str> {
+        self.deny.list_topics(&())
+    }
+    #[inline]
     pub fn is_empty(&self) -> bool {
         self.smap.is_empty()
     }
 }
+
+impl<S> FromIterator<S> for AclMap
+where
+    S: AsRef<str>,
+{
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+        let mut acl_map = Self::new();
+        for topic in iter {
+            acl_map.insert(topic.as_ref());
+        }
+        acl_map
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AclMap;
+    #[test]
+    fn test_contains() {
+        let mut acl = AclMap::new().match_any("+").wildcard("#");
+        acl.insert("a/#");
+        acl.insert("b/+/c");
+        assert!(acl.contains("a/#"));
+        assert!(acl.contains("b/+/c"));
+        assert!(!acl.contains("a/b"));
+        assert!(acl.matches("a/b"));
+        assert!(!acl.contains("b/x/c"));
+        acl.remove("a/#");
+        assert!(!acl.contains("a/#"));
+    }
+    #[test]
+    fn test_remove() {
+        let mut acl = AclMap::new().match_any("+").wildcard("#");
+        acl.insert("a/#");
+        assert!(acl.matches("a/b"));
+        assert!(acl.remove("a/#"));
+        assert!(!acl.matches("a/b"));
+        assert!(!acl.remove("a/#"));
+        assert!(acl.list().is_empty());
+    }
+    #[test]
+    fn test_all_topics() {
+        let acl = AclMap::from_topics(&["*"]);
+        assert!(acl.matches("$SYS/x"));
+        assert!(acl.matches("a/b"));
+    }
+    #[test]
+    fn test_deny() {
+        let mut acl = AclMap::new().separator('.').match_any("+").wildcard("#");
+        acl.insert("a.#");
+        acl.deny("a.secret");
+        acl.deny("a.+.private");
+        assert!(acl.matches("a.public"));
+        assert!(!acl.matches("a.secret"));
+        assert!(acl.matches("a.secret.x"));
+        assert!(!acl.matches("a.b.private"));
+        assert!(!acl.matches("b.public"));
+        assert!(!acl.contains("a.secret"));
+        assert_eq!(acl.list_deny().len(), 2);
+        assert!(acl.remove_deny("a.secret"));
+        assert!(!acl.remove_deny("a.secret"));
+        assert!(acl.matches("a.secret"));
+    }
+    #[test]
+    fn test_matches_any_all() {
+        let mut acl = AclMap::new().match_any("+").wildcard("#");
+        acl.insert("a/#");
+        acl.deny("a/secret");
+        assert!(acl.matches_any(&["b/x", "a/x"]));
+        assert!(!acl.matches_any(&["b/x", "a/secret"]));
+        assert!(!acl.matches_any(&[]));
+        assert!(acl.matches_all(&["a/x", "a/y/z"]));
+        assert!(!acl.matches_all(&["a/x", "a/secret"]));
+        assert!(acl.matches_all(&[]));
+    }
+    #[test]
+    fn test_from_iter() {
+        let topics = vec!["a/*".to_owned(), "b/?/c".to_owned()];
+        let acl: AclMap = topics.into_iter().collect();
+        assert!(acl.matches("a/x/y"));
+        assert!(acl.matches("b/x/c"));
+        assert!(!acl.matches("c"));
+        let acl = AclMap::from_topics(&["x/?", "y"]);
+        assert!(acl.matches("x/1"));
+        assert!(acl.matches("y"));
+        assert!(!acl.matches("x/1/2"));
+        assert_eq!(acl.list().len(), 2);
+    }
+    #[test]
+    fn test_merge() {
+        let mut acl = AclMap::new().match_any("+").wildcard("#");
+        acl.insert("a/#");
+        let mut overlay = AclMap::new().match_any("+").wildcard("#");
+        overlay.insert("b/#");
+        overlay.deny("a/secret");
+        acl.merge(&overlay).unwrap();
+        assert!(acl.matches("a/x"));
+        assert!(acl.matches("b/x"));
+        assert!(!acl.matches("a/secret"));
+        assert!(!acl.matches("c/x"));
+        assert_eq!(acl.list().len(), 2);
+        let other = AclMap::new().separator('.');
+        assert!(matches!(
+            acl.merge(&other),
+            Err(crate::Error::ConfigConflict(_))
+        ));
+        assert_eq!(acl.list().len(), 2);
+    }
+    #[test]
+    fn test_matches_with_reason() {
+        let mut acl = AclMap::new().match_any("+").wildcard("#");
+        acl.insert("a/#");
+        acl.insert("b/+/c");
+        acl.deny("a/secret");
+        assert_eq!(acl.matches_with_reason("a/x").as_deref(), Some("a/#"));
+        assert_eq!(acl.matches_with_reason("b/x/c").as_deref(), Some("b/+/c"));
+        assert!(acl.matches_with_reason("a/secret").is_none());
+        assert!(acl.matches_with_reason("c").is_none());
+    }
+}
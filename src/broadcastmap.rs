@@ -1,5 +1,4 @@
-use std::str::Split;
-
+use crate::submap::{Separator, TopicSplit};
 #[allow(clippy::wildcard_imports)]
 use crate::types::*;
 
@@ -8,13 +7,34 @@ struct Broadcast<C> {
     childs: Map<String, Broadcast<C>>,
     childs_any: Option<Box<Broadcast<C>>>,
     members: Set<C>,
-    members_wildcard: Set<C>,
+    // wildcard members with the number of their names, which pass through the node
+    wildcard_refs: Map<C, usize>,
 }
 
 impl<C> Broadcast<C> {
     #[inline]
     fn is_empty(&self) -> bool {
-        self.childs.is_empty() && self.members.is_empty()
+        self.childs.is_empty()
+            && self.childs_any.is_none()
+            && self.members.is_empty()
+            && self.wildcard_refs.is_empty()
+    }
+}
+
+impl<C> Broadcast<C>
+where
+    C: Client,
+{
+    fn shrink_to_fit(&mut self) {
+        self.members.shrink_to_fit();
+        self.wildcard_refs.shrink_to_fit();
+        self.childs.shrink_to_fit();
+        for child in self.childs.values_mut() {
+            child.shrink_to_fit();
+        }
+        if let Some(ref mut child) = self.childs_any {
+            child.shrink_to_fit();
+        }
     }
 }
 
@@ -24,7 +44,7 @@ impl<C> Default for Broadcast<C> {
             childs: <_>::default(),
             childs_any: <_>::default(),
             members: <_>::default(),
-            members_wildcard: <_>::default(),
+            wildcard_refs: <_>::default(),
         }
     }
 }
@@ -32,7 +52,9 @@ impl<C> Default for Broadcast<C> {
 #[derive(Debug, Clone)]
 pub struct BroadcastMap<C> {
     broadcasts: Broadcast<C>,
-    separator: char,
+    client_names: Map<C, Set<String>>,
+    registration_count: usize,
+    separator: Separator,
     match_any: Set<String>,
     wildcard: Set<String>,
 }
@@ -41,7 +63,9 @@ impl<C> Default for BroadcastMap<C> {
     fn default() -> Self {
         Self {
             broadcasts: Broadcast::default(),
-            separator: '.',
+            client_names: <_>::default(),
+            registration_count: 0,
+            separator: Separator::Char('.'),
             match_any: vec!["?".to_owned()].into_iter().collect(),
             wildcard: vec!["*".to_owned()].into_iter().collect(),
         }
@@ -56,9 +80,29 @@ where
     pub fn new() -> Self {
         Self::default()
     }
+    /// Creates a map with the client name index preallocated for the given number of clients.
+    /// With the default B-tree engine the capacity is ignored and the call is equal to
+    /// [`BroadcastMap::new`]
+    #[inline]
+    pub fn with_capacity(clients: usize) -> Self {
+        Self {
+            client_names: map_with_capacity(clients),
+            ..Self::default()
+        }
+    }
     #[inline]
     pub fn separator(mut self, separator: char) -> Self {
-        self.separator = separator;
+        self.separator = Separator::Char(separator);
+        self
+    }
+    /// Sets a multi-character separator (e.g. "->")
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the separator is empty
+    #[inline]
+    pub fn separator_str(mut self, separator: &str) -> Self {
+        self.separator = Separator::new(separator);
         self
     }
     #[inline]
@@ -85,57 +129,305 @@ where
     pub fn is_empty(&self) -> bool {
         self.broadcasts.is_empty()
     }
-    #[inline]
     pub fn register_client(&mut self, name: &str, client: &C) {
-        register_broadcast_client_rec(&mut self.broadcasts, name.split(self.separator), client);
+        if let Some(names) = self.client_names.get_mut(client) {
+            if !names.insert(name.to_owned()) {
+                return;
+            }
+        } else {
+            self.client_names
+                .insert(client.clone(), vec![name.to_owned()].into_iter().collect());
+        }
+        self.registration_count += 1;
+        register_broadcast_client_rec(&mut self.broadcasts, self.separator.split(name), client);
     }
-    #[inline]
     pub fn unregister_client(&mut self, name: &str, client: &C) {
-        unregister_broadcast_client_rec(&mut self.broadcasts, name.split(self.separator), client);
+        let Some(names) = self.client_names.get_mut(client) else {
+            return;
+        };
+        if !names.remove_key(name) {
+            return;
+        }
+        self.registration_count -= 1;
+        unregister_broadcast_client_rec(&mut self.broadcasts, self.separator.split(name), client);
+        if names.is_empty() {
+            self.client_names.remove_key(client);
+        }
+    }
+    /// Removes all clients and names, keeping the configuration
+    pub fn clear(&mut self) {
+        self.broadcasts = Broadcast::default();
+        self.client_names.clear();
+        self.registration_count = 0;
+    }
+    /// Returns true if the client is registered under the exact name
+    pub fn is_registered(&self, name: &str, client: &C) -> bool {
+        self.exact_node(name)
+            .is_some_and(|broadcast| broadcast.members.contains(client))
+    }
+    /// Returns clients registered under the exact name. Wildcard and match-any tokens in the
+    /// name are treated as literal chunks
+    pub fn get_exact_members(&self, name: &str) -> Set<C> {
+        self.exact_node(name)
+            .map(|broadcast| broadcast.members.clone())
+            .unwrap_or_default()
+    }
+    fn exact_node(&self, name: &str) -> Option<&Broadcast<C>> {
+        let mut broadcast = &self.broadcasts;
+        for chunk in self.separator.split(name) {
+            broadcast = broadcast.childs.get(chunk)?;
+        }
+        Some(broadcast)
+    }
+    /// Returns the total number of name-client registrations
+    #[inline]
+    pub fn registration_count(&self) -> usize {
+        self.registration_count
+    }
+    /// Returns the number of distinct registered clients
+    #[inline]
+    pub fn client_count(&self) -> usize {
+        self.client_names.len()
+    }
+    /// Shrinks the capacity of internal maps and sets of the broadcast tree and the client name
+    /// index as much as possible. A no-op with the default B-tree engine
+    pub fn shrink_to_fit(&mut self) {
+        self.broadcasts.shrink_to_fit();
+        for names in self.client_names.values_mut() {
+            names.shrink_to_fit();
+        }
+        self.client_names.shrink_to_fit();
+    }
+    /// Unregisters the client from all names, returns false if the client is not registered
+    pub fn unregister_all(&mut self, client: &C) -> bool {
+        let Some(names) = self.client_names.remove_key(client) else {
+            return false;
+        };
+        self.registration_count -= names.len();
+        for name in &names {
+            unregister_broadcast_client_rec(
+                &mut self.broadcasts,
+                self.separator.split(name),
+                client,
+            );
+        }
+        true
+    }
+    /// Returns all names the client is registered under
+    pub fn list_client_names(&self, client: &C) -> Vec<&str> {
+        if let Some(names) = self.client_names.get(client) {
+            names.iter().map(String::as_str).collect()
+        } else {
+            Vec::new()
+        }
     }
     pub fn get_clients_by_mask(&self, mask: &str) -> Set<C> {
-        let mut result = Set::new();
+        let mut result = Set::default();
         get_broadcast_clients_rec(
             &self.broadcasts,
-            mask.split(self.separator),
-            &mut result,
+            self.separator.split(mask),
+            &mut |client| {
+                result.insert(client.clone());
+            },
             &self.wildcard,
             &self.match_any,
         );
         result
     }
+    /// Returns all names, having registered clients. Names are listed together with their
+    /// match-any mirrors (e.g. "a.?" for "a.b"), which are built with the first configured
+    /// match-any token
+    pub fn list_names(&self) -> Vec<String> {
+        let mut result = Vec::new();
+        list_names_rec(
+            &self.broadcasts,
+            None,
+            &self.separator,
+            self.match_any.iter().min().map(String::as_str),
+            &mut result,
+        );
+        result
+    }
+    /// Returns an iterator over clients matching the mask, which walks the tree lazily, the
+    /// clients are not cloned. Each client is returned only once
+    pub fn get_clients_by_mask_iter<'a>(
+        &'a self,
+        mask: &'a str,
+    ) -> impl Iterator<Item = &'a C> + 'a {
+        ClientsIter {
+            wildcard: &self.wildcard,
+            match_any: &self.match_any,
+            nodes: vec![(&self.broadcasts, self.separator.split(mask))],
+            current: None,
+            visited: Set::default(),
+        }
+    }
+    /// Calls the function for each client matching the mask, the clients are not cloned. If dedup
+    /// is false, no visited set is allocated and the function must tolerate a client being
+    /// visited more than once
+    pub fn for_each_client<F>(&self, mask: &str, dedup: bool, mut f: F)
+    where
+        F: FnMut(&C),
+    {
+        if dedup {
+            let mut visited: Set<&C> = Set::default();
+            get_broadcast_clients_rec(
+                &self.broadcasts,
+                self.separator.split(mask),
+                &mut |client| {
+                    if visited.insert(client) {
+                        f(client);
+                    }
+                },
+                &self.wildcard,
+                &self.match_any,
+            );
+        } else {
+            get_broadcast_clients_rec(
+                &self.broadcasts,
+                self.separator.split(mask),
+                &mut f,
+                &self.wildcard,
+                &self.match_any,
+            );
+        }
+    }
+    /// Returns the number of distinct clients matching the mask without cloning them
+    pub fn get_clients_by_mask_count(&self, mask: &str) -> usize {
+        let mut result: Set<&C> = Set::default();
+        get_broadcast_clients_rec(
+            &self.broadcasts,
+            self.separator.split(mask),
+            &mut |client| {
+                result.insert(client);
+            },
+            &self.wildcard,
+            &self.match_any,
+        );
+        result.len()
+    }
 }
 
-fn get_broadcast_clients_rec<C>(
-    broadcast: &Broadcast<C>,
-    mut sp: Split<char>,
-    result: &mut Set<C>,
+/// Calls the function for each member of the nodes matching the mask
+fn get_broadcast_clients_rec<'a, C, F>(
+    broadcast: &'a Broadcast<C>,
+    mut sp: TopicSplit,
+    f: &mut F,
     wildcard: &Set<String>,
     match_any: &Set<String>,
 ) where
     C: Client,
+    F: FnMut(&'a C),
 {
     if let Some(chunk) = sp.next() {
         if wildcard.contains(chunk) {
-            result.extend(broadcast.members_wildcard.clone());
+            broadcast.wildcard_refs.keys().for_each(f);
         } else if match_any.contains(chunk) {
             if let Some(ref child) = broadcast.childs_any {
-                get_broadcast_clients_rec(child, sp, result, wildcard, match_any);
+                get_broadcast_clients_rec(child, sp, f, wildcard, match_any);
             }
         } else if let Some(child) = broadcast.childs.get(chunk) {
-            get_broadcast_clients_rec(child, sp, result, wildcard, match_any);
+            get_broadcast_clients_rec(child, sp, f, wildcard, match_any);
         }
     } else {
-        result.extend(broadcast.members.clone());
+        broadcast.members.iter().for_each(f);
+    }
+}
+
+/// Members of a single node, see [`ClientsIter`]
+enum Members<'a, C> {
+    Exact(<&'a Set<C> as IntoIterator>::IntoIter),
+    Wildcard(<&'a Map<C, usize> as IntoIterator>::IntoIter),
+}
+
+impl<'a, C> Iterator for Members<'a, C> {
+    type Item = &'a C;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Members::Exact(members) => members.next(),
+            Members::Wildcard(refs) => refs.next().map(|(client, _)| client),
+        }
     }
 }
 
-fn register_broadcast_client_rec<C>(broadcast: &mut Broadcast<C>, mut sp: Split<char>, client: &C)
+/// A lazy iterator over clients, see [`BroadcastMap::get_clients_by_mask_iter`]
+struct ClientsIter<'a, C> {
+    wildcard: &'a Set<String>,
+    match_any: &'a Set<String>,
+    nodes: Vec<(&'a Broadcast<C>, TopicSplit<'a>)>,
+    current: Option<Members<'a, C>>,
+    visited: Set<&'a C>,
+}
+
+impl<'a, C> Iterator for ClientsIter<'a, C>
+where
+    C: Client,
+{
+    type Item = &'a C;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(ref mut current) = self.current {
+                for client in current.by_ref() {
+                    if self.visited.insert(client) {
+                        return Some(client);
+                    }
+                }
+                self.current = None;
+            }
+            let (broadcast, mut sp) = self.nodes.pop()?;
+            if let Some(chunk) = sp.next() {
+                if self.wildcard.contains(chunk) {
+                    self.current = Some(Members::Wildcard(broadcast.wildcard_refs.iter()));
+                } else if self.match_any.contains(chunk) {
+                    if let Some(ref child) = broadcast.childs_any {
+                        self.nodes.push((child, sp));
+                    }
+                } else if let Some(child) = broadcast.childs.get(chunk) {
+                    self.nodes.push((child, sp));
+                }
+            } else {
+                self.current = Some(Members::Exact(broadcast.members.iter()));
+            }
+        }
+    }
+}
+
+fn list_names_rec<C>(
+    broadcast: &Broadcast<C>,
+    path: Option<&str>,
+    separator: &Separator,
+    match_any: Option<&str>,
+    result: &mut Vec<String>,
+) {
+    if let Some(path) = path {
+        if !broadcast.members.is_empty() {
+            result.push(path.to_owned());
+        }
+    }
+    let childs_any = match_any.zip(broadcast.childs_any.as_deref());
+    for (chunk, child) in broadcast
+        .childs
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .chain(childs_any)
+    {
+        let child_path = if let Some(path) = path {
+            format!("{}{}{}", path, separator, chunk)
+        } else {
+            chunk.to_owned()
+        };
+        list_names_rec(child, Some(&child_path), separator, match_any, result);
+    }
+}
+
+fn register_broadcast_client_rec<C>(broadcast: &mut Broadcast<C>, mut sp: TopicSplit, client: &C)
 where
     C: Client,
 {
     if let Some(chunk) = sp.next() {
-        broadcast.members_wildcard.insert(client.clone());
+        *broadcast.wildcard_refs.entry(client.clone()).or_insert(0) += 1;
         if let Some(c) = broadcast.childs.get_mut(chunk) {
             register_broadcast_client_rec(c, sp.clone(), client);
         } else {
@@ -155,16 +447,21 @@ where
     }
 }
 
-fn unregister_broadcast_client_rec<C>(broadcast: &mut Broadcast<C>, mut sp: Split<char>, client: &C)
+fn unregister_broadcast_client_rec<C>(broadcast: &mut Broadcast<C>, mut sp: TopicSplit, client: &C)
 where
     C: Client,
 {
     if let Some(chunk) = sp.next() {
-        broadcast.members_wildcard.remove(client);
+        if let Some(refs) = broadcast.wildcard_refs.get_mut(client) {
+            *refs -= 1;
+            if *refs == 0 {
+                broadcast.wildcard_refs.remove_key(client);
+            }
+        }
         if let Some(c) = broadcast.childs.get_mut(chunk) {
             unregister_broadcast_client_rec(c, sp.clone(), client);
             if c.is_empty() {
-                broadcast.childs.remove(chunk);
+                broadcast.childs.remove_key(chunk);
             }
         }
         if let Some(ref mut c) = broadcast.childs_any {
@@ -174,7 +471,7 @@ where
             }
         }
     } else {
-        broadcast.members.remove(client);
+        broadcast.members.remove_key(client);
     }
 }
 
@@ -268,4 +565,271 @@ mod test {
         bmap.unregister_client("that/is/a", &client5);
         assert!(bmap.broadcasts.is_empty());
     }
+    #[test]
+    fn test_get_clients_by_mask_count() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new();
+        bmap.register_client("a.b.c", &1);
+        bmap.register_client("a.b.d", &1);
+        bmap.register_client("a.b.d", &2);
+        bmap.register_client("a.x", &3);
+        for mask in ["a.*", "a.b.*", "a.?.d", "a.b.c", "*", "b.*", "?.?"] {
+            assert_eq!(
+                bmap.get_clients_by_mask_count(mask),
+                bmap.get_clients_by_mask(mask).len(),
+                "{}",
+                mask
+            );
+        }
+        assert_eq!(bmap.get_clients_by_mask_count("a.*"), 3);
+        assert_eq!(bmap.get_clients_by_mask_count("a.b.*"), 2);
+        assert_eq!(bmap.get_clients_by_mask_count("b.*"), 0);
+    }
+    #[test]
+    fn test_list_names() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new().separator('/');
+        assert!(bmap.list_names().is_empty());
+        bmap.register_client("this/is/a", &1);
+        bmap.register_client("this/is/b", &2);
+        bmap.register_client("this", &3);
+        bmap.register_client("that/is/a", &1);
+        let mut names = bmap.list_names();
+        names.retain(|name| !name.contains('?'));
+        names.sort();
+        assert_eq!(names, ["that/is/a", "this", "this/is/a", "this/is/b"]);
+        bmap.unregister_client("this/is/b", &2);
+        let mut names = bmap.list_names();
+        names.retain(|name| !name.contains('?'));
+        names.sort();
+        assert_eq!(names, ["that/is/a", "this", "this/is/a"]);
+    }
+    #[test]
+    fn test_list_names_match_any() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new().match_any("+");
+        bmap.register_client("a.b", &1);
+        bmap.register_client("c", &2);
+        let mut names = bmap.list_names();
+        names.sort();
+        assert_eq!(names, ["+", "+.+", "+.b", "a.+", "a.b", "c"]);
+        bmap.unregister_client("a.b", &1);
+        assert_eq!(bmap.list_names().len(), 2);
+        let bmap: BroadcastMap<u32> = BroadcastMap::new().match_any_multiple(&[]);
+        assert!(bmap.list_names().is_empty());
+    }
+    #[test]
+    fn test_client_names() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new();
+        bmap.register_client("a.b.c", &1);
+        bmap.register_client("a.b.d", &1);
+        bmap.register_client("x", &1);
+        bmap.register_client("a.b.d", &2);
+        let mut names = bmap.list_client_names(&1);
+        names.sort_unstable();
+        assert_eq!(names, ["a.b.c", "a.b.d", "x"]);
+        bmap.unregister_client("a.b.c", &1);
+        assert_eq!(bmap.list_client_names(&1).len(), 2);
+        assert!(bmap.get_clients_by_mask("a.*").contains(&1));
+        assert!(bmap.get_clients_by_mask("a.b.*").contains(&1));
+        assert!(bmap.get_clients_by_mask("*").contains(&1));
+        assert!(bmap.unregister_all(&1));
+        assert!(!bmap.unregister_all(&1));
+        assert!(bmap.list_client_names(&1).is_empty());
+        assert!(!bmap.get_clients_by_mask("*").contains(&1));
+        assert!(bmap.get_clients_by_mask("a.b.d").contains(&2));
+        bmap.unregister_client("a.b.d", &2);
+        assert!(bmap.client_names.is_empty());
+    }
+    #[test]
+    fn test_get_clients_by_mask_iter() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new();
+        bmap.register_client("a.b.c", &1);
+        bmap.register_client("a.b.d", &1);
+        bmap.register_client("a.b.d", &2);
+        bmap.register_client("a.x", &3);
+        for mask in ["a.*", "a.b.*", "a.?.d", "a.b.c", "*", "b.*", "?.?"] {
+            let clients: Vec<&u32> = bmap.get_clients_by_mask_iter(mask).collect();
+            let expected = bmap.get_clients_by_mask(mask);
+            assert_eq!(clients.len(), expected.len(), "{}", mask);
+            assert!(clients.iter().all(|c| expected.contains(*c)), "{}", mask);
+        }
+        assert_eq!(bmap.get_clients_by_mask_iter("*").take(1).count(), 1);
+    }
+    #[test]
+    fn test_separator_str() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new().separator_str("->");
+        bmap.register_client("this->is->a", &1);
+        bmap.register_client("this->is->b", &2);
+        bmap.register_client("this->is", &3);
+        bmap.register_client("that->is->a", &4);
+        assert_eq!(bmap.get_clients_by_mask("this->is->*").len(), 2);
+        assert_eq!(bmap.get_clients_by_mask("this->*").len(), 3);
+        assert_eq!(bmap.get_clients_by_mask("*").len(), 4);
+        assert_eq!(bmap.get_clients_by_mask("?->is->a").len(), 2);
+        assert!(bmap.get_clients_by_mask("this->is").contains(&3));
+        assert!(bmap.get_clients_by_mask("this.is").is_empty());
+        let mut names = bmap.list_names();
+        names.retain(|name| !name.contains('?'));
+        names.sort();
+        assert_eq!(
+            names,
+            ["that->is->a", "this->is", "this->is->a", "this->is->b"]
+        );
+        let bmap: BroadcastMap<u32> = BroadcastMap::new().separator_str("/");
+        assert!(matches!(bmap.separator, super::Separator::Char('/')));
+    }
+    #[test]
+    fn test_unregister_deep() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new();
+        bmap.register_client("a.b.c.d.e", &1);
+        bmap.register_client("a.b.x", &2);
+        bmap.unregister_client("a.b.x", &2);
+        assert!(bmap.get_clients_by_mask("a.*").contains(&1));
+        assert!(!bmap.get_clients_by_mask("a.*").contains(&2));
+        assert!(bmap.get_clients_by_mask("?.?.?.?.e").contains(&1));
+        assert!(bmap.get_clients_by_mask("a.?.c.*").contains(&1));
+        bmap.unregister_client("a.b.c.d.e", &1);
+        for mask in ["*", "a.*", "a.b.c.d.e", "?.?.?.?.?", "a.?.c.*"] {
+            assert!(bmap.get_clients_by_mask(mask).is_empty(), "{}", mask);
+        }
+        assert!(bmap.broadcasts.is_empty());
+        assert!(bmap.is_empty());
+        bmap.register_client("a.b", &1);
+        assert!(bmap.unregister_all(&1));
+        assert!(bmap.broadcasts.is_empty());
+    }
+    #[test]
+    fn test_with_capacity() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::with_capacity(100);
+        #[cfg(any(feature = "indexmap", feature = "hashmap"))]
+        assert!(bmap.client_names.capacity() >= 100);
+        for client in 0..100 {
+            bmap.register_client(&format!("unit.{}", client), &client);
+        }
+        assert_eq!(bmap.get_clients_by_mask("unit.*").len(), 100);
+    }
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new();
+        for client in 0..1000 {
+            bmap.register_client(&format!("unit.{}.t", client), &client);
+            bmap.register_client("unit.all", &client);
+        }
+        for client in 2..1000 {
+            bmap.unregister_all(&client);
+        }
+        bmap.shrink_to_fit();
+        assert_eq!(bmap.get_clients_by_mask("unit.*").len(), 2);
+        assert_eq!(bmap.get_clients_by_mask("unit.?.t").len(), 2);
+        assert_eq!(bmap.get_clients_by_mask("unit.1.t").len(), 1);
+        assert!(bmap.get_clients_by_mask("unit.5.t").is_empty());
+        bmap.register_client("unit.5.t", &5);
+        assert_eq!(bmap.get_clients_by_mask("unit.?.t").len(), 3);
+    }
+    #[test]
+    fn test_clear_is_registered() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new().separator('/');
+        bmap.register_client("this/is/a", &1);
+        bmap.register_client("this/is", &2);
+        assert!(bmap.is_registered("this/is/a", &1));
+        assert!(bmap.is_registered("this/is", &2));
+        assert!(!bmap.is_registered("this/is", &1));
+        assert!(!bmap.is_registered("this/*", &1));
+        assert!(!bmap.is_registered("this/?/a", &1));
+        assert!(!bmap.is_registered("other", &1));
+        bmap.unregister_client("this/is/a", &1);
+        assert!(!bmap.is_registered("this/is/a", &1));
+        assert!(bmap.is_registered("this/is", &2));
+        bmap.register_client("this/is/a", &1);
+        bmap.clear();
+        assert!(bmap.is_empty());
+        assert!(!bmap.is_registered("this/is", &2));
+        assert!(bmap.list_client_names(&1).is_empty());
+        bmap.register_client("x/y", &3);
+        assert!(bmap.is_registered("x/y", &3));
+        assert!(bmap.get_clients_by_mask("x/*").contains(&3));
+    }
+    #[test]
+    fn test_counts() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new();
+        assert_eq!(bmap.registration_count(), 0);
+        assert_eq!(bmap.client_count(), 0);
+        bmap.register_client("a.b.c", &1);
+        bmap.register_client("a.b.d", &1);
+        bmap.register_client("a.b.d", &1);
+        bmap.register_client("a.b.d", &2);
+        bmap.register_client("x", &3);
+        assert_eq!(bmap.registration_count(), 4);
+        assert_eq!(bmap.client_count(), 3);
+        bmap.unregister_client("a.b.c", &1);
+        bmap.unregister_client("a.b.c", &1);
+        bmap.unregister_client("a.b.c", &2);
+        assert_eq!(bmap.registration_count(), 3);
+        assert_eq!(bmap.client_count(), 3);
+        bmap.unregister_client("x", &3);
+        assert_eq!(bmap.registration_count(), 2);
+        assert_eq!(bmap.client_count(), 2);
+        assert!(bmap.unregister_all(&1));
+        assert_eq!(bmap.registration_count(), 1);
+        assert_eq!(bmap.client_count(), 1);
+        bmap.register_client("y", &4);
+        bmap.clear();
+        assert_eq!(bmap.registration_count(), 0);
+        assert_eq!(bmap.client_count(), 0);
+    }
+    #[test]
+    fn test_get_exact_members() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new().separator('/');
+        bmap.register_client("this/is/a", &1);
+        bmap.register_client("this/is", &2);
+        bmap.register_client("this", &3);
+        let members = bmap.get_exact_members("this/is");
+        assert_eq!(members.len(), 1);
+        assert!(members.contains(&2));
+        assert!(bmap.get_clients_by_mask("this/*").contains(&1));
+        assert!(bmap.get_exact_members("this/*").is_empty());
+        assert!(bmap.get_exact_members("this/?").is_empty());
+        assert!(bmap.get_exact_members("that").is_empty());
+        assert!(bmap.get_exact_members("this/is/a").contains(&1));
+    }
+    #[test]
+    fn test_for_each_client() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new();
+        bmap.register_client("a.b.c", &1);
+        bmap.register_client("x.b.c", &1);
+        bmap.register_client("a.b.d", &2);
+        for mask in ["a.*", "?.b.c", "?.b.?", "*", "a.b.c", "b.*"] {
+            let mut expected: Vec<u32> = bmap.get_clients_by_mask(mask).into_iter().collect();
+            expected.sort_unstable();
+            for dedup in [true, false] {
+                let mut clients = Vec::new();
+                bmap.for_each_client(mask, dedup, |c| clients.push(*c));
+                clients.sort_unstable();
+                if !dedup {
+                    clients.dedup();
+                }
+                assert_eq!(clients, expected, "{} {}", mask, dedup);
+            }
+        }
+        let mut count = 0;
+        bmap.for_each_client("?.b.c", true, |_| count += 1);
+        assert_eq!(count, 1);
+    }
+    #[test]
+    fn test_unregister_shared_path() {
+        let mut bmap: BroadcastMap<u32> = BroadcastMap::new();
+        bmap.register_client("a.b.c", &1);
+        bmap.register_client("a.x.c", &1);
+        bmap.register_client("a.b.d", &1);
+        // repeated registrations are not counted twice
+        bmap.register_client("a.b.d", &1);
+        bmap.unregister_client("a.b.d", &1);
+        assert!(bmap.get_clients_by_mask("a.b.*").contains(&1));
+        bmap.unregister_client("a.b.c", &1);
+        assert!(!bmap.get_clients_by_mask("a.b.*").contains(&1));
+        for mask in ["*", "a.*", "?.?.*", "a.?.*"] {
+            assert!(bmap.get_clients_by_mask(mask).contains(&1), "{}", mask);
+        }
+        bmap.unregister_client("a.x.c", &1);
+        assert!(bmap.get_clients_by_mask("*").is_empty());
+        assert!(bmap.is_empty());
+    }
 }
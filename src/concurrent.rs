@@ -0,0 +1,291 @@
+use crate::submap::SubMap;
+#[allow(clippy::wildcard_imports)]
+use crate::types::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A thread-safe [`SubMap`] wrapper. Query methods take the read lock, mutating ones take the
+/// write lock. Use [`ConcurrentSubMap::read`] and [`ConcurrentSubMap::write`] to perform batched
+/// operations under a single lock.
+///
+/// A poisoned lock is recovered, as the map is kept consistent by each single operation
+#[derive(Debug, Default)]
+pub struct ConcurrentSubMap<C> {
+    inner: RwLock<SubMap<C>>,
+}
+
+impl<C> From<SubMap<C>> for ConcurrentSubMap<C> {
+    fn from(smap: SubMap<C>) -> Self {
+        Self {
+            inner: RwLock::new(smap),
+        }
+    }
+}
+
+impl<C> ConcurrentSubMap<C>
+where
+    C: Client,
+{
+    #[inline]
+    pub fn new() -> Self {
+        SubMap::new().into()
+    }
+    /// Returns the read guard of the inner map
+    #[inline]
+    pub fn read(&self) -> RwLockReadGuard<'_, SubMap<C>> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Returns the write guard of the inner map
+    #[inline]
+    pub fn write(&self) -> RwLockWriteGuard<'_, SubMap<C>> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+    #[inline]
+    pub fn into_inner(self) -> SubMap<C> {
+        self.inner
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    #[inline]
+    pub fn register_client(&self, client: &C) -> bool {
+        self.write().register_client(client)
+    }
+    #[inline]
+    pub fn unregister_client(&self, client: &C) -> bool {
+        self.write().unregister_client(client)
+    }
+    #[inline]
+    pub fn subscribe(&self, topic: &str, client: &C) -> bool {
+        self.write().subscribe(topic, client)
+    }
+    #[inline]
+    pub fn unsubscribe(&self, topic: &str, client: &C) -> bool {
+        self.write().unsubscribe(topic, client)
+    }
+    #[inline]
+    pub fn unsubscribe_all(&self, client: &C) -> bool {
+        self.write().unsubscribe_all(client)
+    }
+    /// Returns the topic subscribers. The result set is cloned under the read lock, which is
+    /// released before the method returns, so the delivery does not block writers
+    #[inline]
+    pub fn get_subscribers(&self, topic: &str) -> Set<C> {
+        self.read().get_subscribers(topic)
+    }
+    #[inline]
+    pub fn get_subscribers_count(&self, topic: &str) -> usize {
+        self.read().get_subscribers_count(topic)
+    }
+    #[inline]
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.read().is_subscribed(topic)
+    }
+    #[inline]
+    pub fn list_topics_owned(&self, client: &C) -> Vec<String> {
+        self.read().list_topics_owned(client)
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+    #[inline]
+    pub fn subscription_count(&self) -> usize {
+        self.read().subscription_count()
+    }
+    #[inline]
+    pub fn client_count(&self) -> usize {
+        self.read().client_count()
+    }
+}
+
+/// A [`SubMap`] split into multiple shards, each behind its own lock. Clients are distributed
+/// across the shards by their hashes, so operations of different clients rarely contend.
+///
+/// Client operations lock a single shard only, while topic queries fan out across all shards
+/// and union the results, which makes queries more expensive than with [`ConcurrentSubMap`]
+#[derive(Debug)]
+pub struct ShardedSubMap<C> {
+    shards: Vec<ConcurrentSubMap<C>>,
+}
+
+impl<C> ShardedSubMap<C>
+where
+    C: Client + Hash,
+{
+    /// Creates a sharded map with the default configuration
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the number of shards is zero
+    #[inline]
+    pub fn new(shards: usize) -> Self {
+        Self::with_template(&SubMap::new(), shards)
+    }
+    /// Creates a sharded map, each shard has the configuration of the template map. Subscriptions
+    /// of the template are not copied
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the number of shards is zero
+    pub fn with_template(template: &SubMap<C>, shards: usize) -> Self {
+        assert!(shards > 0, "the number of shards must be positive");
+        let mut smap = template.clone();
+        smap.clear();
+        Self {
+            shards: (0..shards).map(|_| smap.clone().into()).collect(),
+        }
+    }
+    #[inline]
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+    /// Returns the shard the client belongs to
+    pub fn shard(&self, client: &C) -> &ConcurrentSubMap<C> {
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+        #[allow(clippy::cast_possible_truncation)]
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+    #[inline]
+    pub fn register_client(&self, client: &C) -> bool {
+        self.shard(client).register_client(client)
+    }
+    #[inline]
+    pub fn unregister_client(&self, client: &C) -> bool {
+        self.shard(client).unregister_client(client)
+    }
+    #[inline]
+    pub fn subscribe(&self, topic: &str, client: &C) -> bool {
+        self.shard(client).subscribe(topic, client)
+    }
+    #[inline]
+    pub fn unsubscribe(&self, topic: &str, client: &C) -> bool {
+        self.shard(client).unsubscribe(topic, client)
+    }
+    #[inline]
+    pub fn unsubscribe_all(&self, client: &C) -> bool {
+        self.shard(client).unsubscribe_all(client)
+    }
+    #[inline]
+    pub fn list_topics_owned(&self, client: &C) -> Vec<String> {
+        self.shard(client).list_topics_owned(client)
+    }
+    /// Returns the topic subscribers, collected from all shards. Each shard is read-locked only
+    /// while its subscribers are collected
+    pub fn get_subscribers(&self, topic: &str) -> Set<C> {
+        let mut result = Set::default();
+        for shard in &self.shards {
+            result.extend(shard.get_subscribers(topic));
+        }
+        result
+    }
+    /// Returns the number of topic subscribers. Shards have no clients in common, so the counts
+    /// are summed
+    pub fn get_subscribers_count(&self, topic: &str) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.get_subscribers_count(topic))
+            .sum()
+    }
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.shards.iter().any(|shard| shard.is_subscribed(topic))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(ConcurrentSubMap::is_empty)
+    }
+    pub fn subscription_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(ConcurrentSubMap::subscription_count)
+            .sum()
+    }
+    pub fn client_count(&self) -> usize {
+        self.shards.iter().map(ConcurrentSubMap::client_count).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConcurrentSubMap, ShardedSubMap};
+    use crate::SubMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_concurrent() {
+        let smap: Arc<ConcurrentSubMap<u32>> =
+            Arc::new(SubMap::new().match_any("+").wildcard("#").into());
+        let writers: Vec<_> = (0..4)
+            .map(|client| {
+                let smap = smap.clone();
+                thread::spawn(move || {
+                    smap.register_client(&client);
+                    for i in 0..100 {
+                        smap.subscribe(&format!("unit/{}/{}", client, i), &client);
+                    }
+                    smap.subscribe("unit/#", &client);
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let smap = smap.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        assert!(smap.get_subscribers("unit/x").len() <= 4);
+                    }
+                })
+            })
+            .collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+        assert_eq!(smap.client_count(), 4);
+        assert_eq!(smap.subscription_count(), 404);
+        assert_eq!(smap.get_subscribers("unit/2/5").len(), 4);
+        {
+            let mut smap = smap.write();
+            smap.unsubscribe_all(&0);
+            smap.unsubscribe_all(&1);
+        }
+        assert_eq!(smap.get_subscribers("unit/x").len(), 2);
+        assert!(smap.read().is_subscribed("unit/3/99"));
+    }
+    #[test]
+    fn test_sharded() {
+        let template = SubMap::new().match_any("+").wildcard("#");
+        let smap: Arc<ShardedSubMap<u32>> = Arc::new(ShardedSubMap::with_template(&template, 4));
+        assert_eq!(smap.shard_count(), 4);
+        let writers: Vec<_> = (0..8)
+            .map(|client| {
+                let smap = smap.clone();
+                thread::spawn(move || {
+                    smap.register_client(&client);
+                    for i in 0..100 {
+                        smap.subscribe(&format!("unit/{}/{}", client, i), &client);
+                    }
+                    smap.subscribe("+/common", &client);
+                })
+            })
+            .collect();
+        for handle in writers {
+            handle.join().unwrap();
+        }
+        assert_eq!(smap.client_count(), 8);
+        assert_eq!(smap.subscription_count(), 808);
+        assert_eq!(smap.get_subscribers("x/common").len(), 8);
+        assert_eq!(smap.get_subscribers_count("x/common"), 8);
+        let clients = smap.get_subscribers("unit/3/7");
+        assert_eq!(clients.len(), 1);
+        assert!(clients.contains(&3));
+        assert!(smap.unsubscribe_all(&3));
+        assert!(!smap.is_subscribed("unit/3/7"));
+        assert_eq!(smap.get_subscribers_count("x/common"), 7);
+        assert_eq!(smap.list_topics_owned(&5).len(), 101);
+        for client in 0..8 {
+            smap.unregister_client(&client);
+        }
+        assert!(smap.is_empty());
+    }
+}
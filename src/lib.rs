@@ -1,6 +1,6 @@
 #![ doc = include_str!( concat!( env!( "CARGO_MANIFEST_DIR" ), "/", "README.md" ) ) ]
 mod submap;
-pub use crate::submap::SubMap;
+pub use crate::submap::{ClientEntry, SubChange, SubMap, SubMapConfig, SubMapDiff, TokenReport};
 
 mod broadcastmap;
 pub use crate::broadcastmap::BroadcastMap;
@@ -8,7 +8,16 @@ pub use crate::broadcastmap::BroadcastMap;
 mod aclmap;
 pub use crate::aclmap::AclMap;
 
-#[cfg(feature = "digest")]
+#[cfg(feature = "sync")]
+mod concurrent;
+#[cfg(feature = "sync")]
+pub use crate::concurrent::{ConcurrentSubMap, ShardedSubMap};
+
+// blake3 alone provides the pure-Rust digest module, with native-digest it extends the OpenSSL one
+#[cfg(any(
+    feature = "digest",
+    all(feature = "blake3", not(feature = "native-digest"))
+))]
 pub mod digest;
 
 #[cfg(feature = "native-digest")]
@@ -17,33 +26,187 @@ pub mod digest;
 
 pub mod mkmf;
 
-#[cfg(not(feature = "indexmap"))]
+#[cfg(all(feature = "indexmap", feature = "hashmap"))]
+compile_error!("features \"indexmap\" and \"hashmap\" are mutually exclusive, select one engine");
+
+#[cfg(all(
+    feature = "fxhash",
+    not(any(feature = "indexmap", feature = "hashmap"))
+))]
+compile_error!("feature \"fxhash\" requires the \"indexmap\" or \"hashmap\" engine");
+
+#[cfg(not(any(feature = "indexmap", feature = "hashmap")))]
 pub mod types {
+    use std::borrow::Borrow;
     use std::collections::{BTreeMap, BTreeSet};
 
     pub type Set<V> = BTreeSet<V>;
     pub type Map<K, V> = BTreeMap<K, V>;
+    /// Map client trait, implemented automatically. Fixed-size arrays, including digests (e.g.
+    /// `[u64; 4]`), can be used as clients as well
     pub trait Client: Ord + Eq + Clone {}
     impl<T: Ord + Eq + Clone> Client for T {}
 
     pub const ENGINE: &str = "std-btree";
+
+    /// B-tree maps can not preallocate, an empty map is returned
+    #[inline]
+    pub(crate) fn map_with_capacity<K, V>(_capacity: usize) -> Map<K, V> {
+        Map::default()
+    }
+
+    /// B-tree collections do not over-allocate, so shrinking is a no-op (hash-based engines use
+    /// the inherent methods)
+    pub(crate) trait ShrinkToFit {
+        #[inline]
+        fn shrink_to_fit(&mut self) {}
+    }
+    impl<K, V> ShrinkToFit for Map<K, V> {}
+    impl<V> ShrinkToFit for Set<V> {}
+
+    /// Engine-neutral key removal, a plain remove here (the indexmap engine has to pick between
+    /// swap and shift removal, as IndexMap::remove is deprecated)
+    pub(crate) trait RemoveKey<Q: ?Sized> {
+        type Output;
+        fn remove_key(&mut self, key: &Q) -> Self::Output;
+    }
+    impl<K: Borrow<Q> + Ord, V, Q: ?Sized + Ord> RemoveKey<Q> for Map<K, V> {
+        type Output = Option<V>;
+        #[inline]
+        fn remove_key(&mut self, key: &Q) -> Option<V> {
+            self.remove(key)
+        }
+    }
+    impl<V: Borrow<Q> + Ord, Q: ?Sized + Ord> RemoveKey<Q> for Set<V> {
+        type Output = bool;
+        #[inline]
+        fn remove_key(&mut self, key: &Q) -> bool {
+            self.remove(key)
+        }
+    }
 }
 
 #[cfg(feature = "indexmap")]
 pub mod types {
-    use indexmap::{IndexMap, IndexSet};
+    use indexmap::{Equivalent, IndexMap, IndexSet};
     use std::hash::Hash;
 
-    pub type Set<V> = IndexSet<V>;
-    pub type Map<K, V> = IndexMap<K, V>;
+    /// Hash builder of the engine maps and sets
+    #[cfg(not(feature = "fxhash"))]
+    pub type HashBuilder = std::collections::hash_map::RandomState;
+    /// Hash builder of the engine maps and sets
+    #[cfg(feature = "fxhash")]
+    pub type HashBuilder = rustc_hash::FxBuildHasher;
+
+    pub type Set<V> = IndexSet<V, HashBuilder>;
+    pub type Map<K, V> = IndexMap<K, V, HashBuilder>;
+    /// Map client trait, implemented automatically. Fixed-size arrays, including digests (e.g.
+    /// `[u64; 4]`), can be used as clients as well
     pub trait Client: Ord + Eq + Clone + Hash {}
     impl<T: Ord + Eq + Clone + Hash> Client for T {}
 
     pub const ENGINE: &str = "indexmap";
+
+    #[inline]
+    pub(crate) fn map_with_capacity<K, V>(capacity: usize) -> Map<K, V> {
+        Map::with_capacity_and_hasher(capacity, HashBuilder::default())
+    }
+
+    /// Engine-neutral key removal, the order of the remaining entries is not preserved
+    pub(crate) trait RemoveKey<Q: ?Sized> {
+        type Output;
+        fn remove_key(&mut self, key: &Q) -> Self::Output;
+    }
+    impl<K: Hash + Eq, V, Q: ?Sized + Hash + Equivalent<K>> RemoveKey<Q> for Map<K, V> {
+        type Output = Option<V>;
+        #[inline]
+        fn remove_key(&mut self, key: &Q) -> Option<V> {
+            self.swap_remove(key)
+        }
+    }
+    impl<V: Hash + Eq, Q: ?Sized + Hash + Equivalent<V>> RemoveKey<Q> for Set<V> {
+        type Output = bool;
+        #[inline]
+        fn remove_key(&mut self, key: &Q) -> bool {
+            self.swap_remove(key)
+        }
+    }
+}
+
+#[cfg(all(feature = "hashmap", not(feature = "indexmap")))]
+pub mod types {
+    use std::borrow::Borrow;
+    use std::collections::{HashMap, HashSet};
+    use std::hash::Hash;
+
+    /// Hash builder of the engine maps and sets
+    #[cfg(not(feature = "fxhash"))]
+    pub type HashBuilder = std::collections::hash_map::RandomState;
+    /// Hash builder of the engine maps and sets
+    #[cfg(feature = "fxhash")]
+    pub type HashBuilder = rustc_hash::FxBuildHasher;
+
+    pub type Set<V> = HashSet<V, HashBuilder>;
+    pub type Map<K, V> = HashMap<K, V, HashBuilder>;
+    /// Map client trait, implemented automatically. Fixed-size arrays, including digests (e.g.
+    /// `[u64; 4]`), can be used as clients as well
+    pub trait Client: Eq + Clone + Hash {}
+    impl<T: Eq + Clone + Hash> Client for T {}
+
+    pub const ENGINE: &str = "std-hash";
+
+    #[inline]
+    pub(crate) fn map_with_capacity<K, V>(capacity: usize) -> Map<K, V> {
+        Map::with_capacity_and_hasher(capacity, HashBuilder::default())
+    }
+
+    /// Engine-neutral key removal, a plain remove here (the indexmap engine has to pick between
+    /// swap and shift removal, as IndexMap::remove is deprecated)
+    pub(crate) trait RemoveKey<Q: ?Sized> {
+        type Output;
+        fn remove_key(&mut self, key: &Q) -> Self::Output;
+    }
+    impl<K: Borrow<Q> + Hash + Eq, V, Q: ?Sized + Hash + Eq> RemoveKey<Q> for Map<K, V> {
+        type Output = Option<V>;
+        #[inline]
+        fn remove_key(&mut self, key: &Q) -> Option<V> {
+            self.remove(key)
+        }
+    }
+    impl<V: Borrow<Q> + Hash + Eq, Q: ?Sized + Hash + Eq> RemoveKey<Q> for Set<V> {
+        type Output = bool;
+        #[inline]
+        fn remove_key(&mut self, key: &Q) -> bool {
+            self.remove(key)
+        }
+    }
 }
 
+/// Errors of topic parsing and map configuration. The enum is non-exhaustive, so new variants
+/// may be added in minor releases and matches on it must have a wildcard arm
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("formula parse: {0}")]
     FormulaParseError(String),
+    #[error("unknown formula function: {0}")]
+    UnknownFunction(String),
+    #[error("formula bracket not closed in {0}")]
+    UnclosedBracket(String),
+    #[error("formula value parse error in {input}: {source}")]
+    ValueParse {
+        input: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("regex parse: {0}")]
+    RegexParseError(String),
+    #[error("hex parse: {0}")]
+    HexParseError(String),
+    #[error("config conflict: {0}")]
+    ConfigConflict(String),
+    #[error("topic too deep, max levels: {0}")]
+    TopicTooDeep(usize),
+    #[error("wildcard is not the last level in {0}")]
+    WildcardPosition(String),
 }
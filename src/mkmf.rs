@@ -11,8 +11,25 @@
 //! - `ge(n)`: greater than or equal to n
 //! - `le(n)`: less than or equal to n
 //! - `ri(n..m)`: range from n to m
+//! - `rx(n..m)`: range from n to m, excluding m
+//! - `mod(n,r)`: the remainder of the euclidean division by n equals r (e.g. `mod(3,1)` matches
+//!   1, 4 and -2)
+//! - `in(a|b|c)`: equal to any of the listed values
+//! - `sw(s)`, `ew(s)`, `ct(s)`: the key starts with, ends with or contains the string (the key
+//!   is not parsed as a number)
+//! - `len(n)`: the key is n bytes long (the key is not parsed as a number)
+//! - `gtf(n)`, `ltf(n)`, `gef(n)`, `lef(n)`, `rif(n..m)`: same as above but for floats
 //!
-//! A key is parsed as i64 before comparison.
+//! Functions can be combined with `&` (and) and `|` (or), e.g. `ge(10) & lt(20) | eq(0)`, `&`
+//! binds tighter than `|`. A function can be negated with a leading `!`, e.g. `!ri(10..20)`. As
+//! with `ne`, a negated numeric function matches non-numeric keys as well. Combinations can be
+//! grouped with parentheses, e.g. `!(ge(10) & lt(20))` or `ge(0) & (lt(10) | gt(20))`.
+//!
+//! A key is parsed as i128 before comparison (f64 for float functions), so both i64 and u64
+//! values (e.g. sequence numbers above `i64::MAX`) are supported. Keys and arguments out of the
+//! i128 range can not be parsed, such keys match `ne` only. Float values are compared
+//! as-is, with no epsilon, so e.g. `lef(0.3)` does not match "0.30000000000000004". NaN never
+//! matches.
 //!
 //! # Example
 //!
@@ -64,15 +81,162 @@ pub trait MapKeysMatchFormula<K, V> {
     fn values_match_key_formula<'a>(&'a self, formula: &'a str) -> impl Iterator<Item = &'a V>
     where
         V: 'a;
+    /// Same as `keys_match_formula` but the keys are sorted by their numeric values, non-numeric
+    /// keys are placed last in the map iteration order
+    fn keys_match_formula_sorted<'a>(&'a self, formula: &'a str) -> Vec<&'a K>
+    where
+        K: AsRef<str> + 'a,
+    {
+        let mut keys: Vec<&K> = self.keys_match_formula(formula).collect();
+        keys.sort_by_cached_key(|key| {
+            let value = key.as_ref().parse::<Int>().ok();
+            (value.is_none(), value)
+        });
+        keys
+    }
+    /// Same as `keys_match_formula` but returns an error if the formula is invalid, instead of
+    /// matching nothing
+    fn try_keys_match_formula<'a>(
+        &'a self,
+        formula: &'a str,
+    ) -> Result<impl Iterator<Item = &'a K>, Error>
+    where
+        K: 'a,
+    {
+        formula.parse::<Formula>()?;
+        Ok(self.keys_match_formula(formula))
+    }
+    /// Returns the number of keys matching the formula
+    fn count_keys_match_formula(&self, formula: &str) -> usize {
+        self.keys_match_formula(formula).count()
+    }
 }
 
+/// A parsed formula. Formulas are usually parsed from strings, but can be constructed in code as
+/// well:
+///
+/// ```rust
+/// use submap::mkmf::Formula;
+///
+/// let f = Formula::ge(4).with_prefix("a");
+/// assert!(f.matches("a5"));
+/// assert!(!f.matches("a3"));
+/// assert_eq!(f, "a#ge(4)".parse().unwrap());
+/// let f = !Formula::ri(10, 20);
+/// assert!(f.matches("5"));
+/// assert!(!f.matches("15"));
+/// ```
 #[derive(Debug, Clone, PartialEq, Eq, Ord, PartialOrd, Hash)]
 pub struct Formula {
     prefix: Option<String>,
     calc: FormulaCalc,
 }
 
+macro_rules! formula_int_fn {
+    ($name:ident, $variant:ident, $doc:expr) => {
+        #[doc = $doc]
+        #[inline]
+        pub fn $name(value: impl Into<i128>) -> Self {
+            Self::from_calc(FormulaCalc::$variant(value.into()))
+        }
+    };
+}
+
+macro_rules! formula_float_fn {
+    ($name:ident, $variant:ident, $doc:expr) => {
+        #[doc = $doc]
+        #[inline]
+        pub fn $name(value: f64) -> Self {
+            Self::from_calc(FormulaCalc::$variant(Float(value)))
+        }
+    };
+}
+
 impl Formula {
+    #[inline]
+    fn from_calc(calc: FormulaCalc) -> Self {
+        Self { prefix: None, calc }
+    }
+    /// Sets the key prefix, which is stripped before matching
+    #[inline]
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+    formula_int_fn!(eq, Eq, "`eq(n)`: equal to n");
+    formula_int_fn!(ne, Ne, "`ne(n)`: not equal to n");
+    formula_int_fn!(gt, Gt, "`gt(n)`: greater than n");
+    formula_int_fn!(lt, Lt, "`lt(n)`: less than n");
+    formula_int_fn!(ge, Ge, "`ge(n)`: greater than or equal to n");
+    formula_int_fn!(le, Le, "`le(n)`: less than or equal to n");
+    formula_float_fn!(gtf, GtF, "`gtf(n)`: greater than n (float)");
+    formula_float_fn!(ltf, LtF, "`ltf(n)`: less than n (float)");
+    formula_float_fn!(gef, GeF, "`gef(n)`: greater than or equal to n (float)");
+    formula_float_fn!(lef, LeF, "`lef(n)`: less than or equal to n (float)");
+    /// `ri(n..m)`: range from n to m
+    #[inline]
+    pub fn ri(min: impl Into<i128>, max: impl Into<i128>) -> Self {
+        Self::from_calc(FormulaCalc::Ri(min.into(), max.into()))
+    }
+    /// `rx(n..m)`: range from n to m, excluding m
+    #[inline]
+    pub fn rx(min: impl Into<i128>, max: impl Into<i128>) -> Self {
+        Self::from_calc(FormulaCalc::Rx(min.into(), max.into()))
+    }
+    /// `rif(n..m)`: range from n to m (float)
+    #[inline]
+    pub fn rif(min: f64, max: f64) -> Self {
+        Self::from_calc(FormulaCalc::RiF(Float(min), Float(max)))
+    }
+    /// `mod(n,r)`: the remainder of the euclidean division by n equals r
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the divisor is zero, use [`Formula::try_modulo`] for divisors which are not
+    /// known in advance
+    #[inline]
+    pub fn modulo(divisor: impl Into<i128>, remainder: impl Into<i128>) -> Self {
+        Self::try_modulo(divisor, remainder).expect("zero divisor")
+    }
+    /// Same as [`Formula::modulo`] but returns an error if the divisor is zero
+    pub fn try_modulo(divisor: impl Into<i128>, remainder: impl Into<i128>) -> Result<Self, Error> {
+        let divisor = divisor.into();
+        if divisor == 0 {
+            return Err(Error::FormulaParseError("zero divisor in mod".to_owned()));
+        }
+        Ok(Self::from_calc(FormulaCalc::Mod(divisor, remainder.into())))
+    }
+    /// `in(a|b|c)`: equal to any of the values
+    #[inline]
+    pub fn any_of<I, V>(values: I) -> Self
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<i128>,
+    {
+        Self::from_calc(FormulaCalc::In(
+            values.into_iter().map(Into::into).collect(),
+        ))
+    }
+    /// `sw(s)`: the key starts with the string
+    #[inline]
+    pub fn sw(s: &str) -> Self {
+        Self::from_calc(FormulaCalc::Sw(s.to_owned()))
+    }
+    /// `ew(s)`: the key ends with the string
+    #[inline]
+    pub fn ew(s: &str) -> Self {
+        Self::from_calc(FormulaCalc::Ew(s.to_owned()))
+    }
+    /// `ct(s)`: the key contains the string
+    #[inline]
+    pub fn ct(s: &str) -> Self {
+        Self::from_calc(FormulaCalc::Ct(s.to_owned()))
+    }
+    /// `len(n)`: the key is n bytes long
+    #[inline]
+    pub fn len(len: usize) -> Self {
+        Self::from_calc(FormulaCalc::Len(len))
+    }
     pub fn matches<S>(&self, value: S) -> bool
     where
         S: AsRef<str>,
@@ -85,6 +249,29 @@ impl Formula {
         }
         self.calc.matches(value)
     }
+    /// Checks if there is a value both formulas match. Formulas with different prefixes, one of
+    /// which starts with another, are considered as intersecting
+    pub(crate) fn intersects(&self, other: &Formula) -> bool {
+        let p1 = self.prefix.as_deref().unwrap_or_default();
+        let p2 = other.prefix.as_deref().unwrap_or_default();
+        if p1 == p2 {
+            self.calc.intersects(&other.calc)
+        } else {
+            p1.starts_with(p2) || p2.starts_with(p1)
+        }
+    }
+}
+
+impl std::ops::Not for Formula {
+    type Output = Formula;
+
+    /// Negates the formula, the prefix is kept and still must match
+    fn not(self) -> Self::Output {
+        Formula {
+            prefix: self.prefix,
+            calc: FormulaCalc::Not(Box::new(self.calc)),
+        }
+    }
 }
 
 impl FromStr for Formula {
@@ -108,15 +295,155 @@ impl FromStr for Formula {
     }
 }
 
+/// Formats the formula in its canonical form, e.g. `a#ge(4)` or `ge(10)&lt(20)`, which can be
+/// parsed back. Negated combinations are grouped with parentheses, e.g. `!(ge(1)&lt(3))`
+impl std::fmt::Display for Formula {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(ref prefix) = self.prefix {
+            write!(f, "{}#", prefix)?;
+        }
+        write!(f, "{}", self.calc)
+    }
+}
+
+/// A float formula argument, compared and hashed by its bits, so formulas can be used as map
+/// keys
+#[derive(Debug, Clone, Copy)]
+struct Float(f64);
+
+impl PartialEq for Float {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.total_cmp(&other.0).is_eq()
+    }
+}
+
+impl Eq for Float {}
+
+impl PartialOrd for Float {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Float {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl std::fmt::Display for Float {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::hash::Hash for Float {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+impl FromStr for Float {
+    type Err = std::num::ParseFloatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Float)
+    }
+}
+
+/// An integer formula argument, wide enough to hold both i64 and u64 values
+type Int = i128;
+
 #[derive(Debug, Clone, PartialEq, Eq, Ord, PartialOrd, Hash)]
 enum FormulaCalc {
-    Eq(i64),
-    Ne(i64),
-    Gt(i64),
-    Lt(i64),
-    Ge(i64),
-    Le(i64),
-    Ri(i64, i64),
+    Eq(Int),
+    Ne(Int),
+    Gt(Int),
+    Lt(Int),
+    Ge(Int),
+    Le(Int),
+    Ri(Int, Int),
+    Rx(Int, Int),
+    GtF(Float),
+    LtF(Float),
+    GeF(Float),
+    LeF(Float),
+    RiF(Float, Float),
+    Mod(Int, Int),
+    In(Vec<Int>),
+    And(Vec<FormulaCalc>),
+    Or(Vec<FormulaCalc>),
+    Not(Box<FormulaCalc>),
+    Sw(String),
+    Ew(String),
+    Ct(String),
+    Len(usize),
+}
+
+impl std::fmt::Display for FormulaCalc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |f: &mut std::fmt::Formatter<'_>, calcs: &[FormulaCalc], op: &str| {
+            for (i, calc) in calcs.iter().enumerate() {
+                if i > 0 {
+                    write!(f, "{}", op)?;
+                }
+                // "|" binds weaker than "&"
+                if op == "&" && matches!(calc, FormulaCalc::Or(_)) {
+                    write!(f, "({})", calc)?;
+                } else {
+                    write!(f, "{}", calc)?;
+                }
+            }
+            Ok(())
+        };
+        match self {
+            FormulaCalc::Eq(v) => write!(f, "eq({})", v),
+            FormulaCalc::Ne(v) => write!(f, "ne({})", v),
+            FormulaCalc::Gt(v) => write!(f, "gt({})", v),
+            FormulaCalc::Lt(v) => write!(f, "lt({})", v),
+            FormulaCalc::Ge(v) => write!(f, "ge({})", v),
+            FormulaCalc::Le(v) => write!(f, "le({})", v),
+            FormulaCalc::Ri(v1, v2) => write!(f, "ri({}..{})", v1, v2),
+            FormulaCalc::Rx(v1, v2) => write!(f, "rx({}..{})", v1, v2),
+            FormulaCalc::GtF(v) => write!(f, "gtf({})", v),
+            FormulaCalc::LtF(v) => write!(f, "ltf({})", v),
+            FormulaCalc::GeF(v) => write!(f, "gef({})", v),
+            FormulaCalc::LeF(v) => write!(f, "lef({})", v),
+            FormulaCalc::RiF(v1, v2) => write!(f, "rif({}..{})", v1, v2),
+            FormulaCalc::Mod(n, r) => write!(f, "mod({},{})", n, r),
+            FormulaCalc::In(values) => {
+                write!(f, "in(")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, ")")
+            }
+            FormulaCalc::And(calcs) => join(f, calcs, "&"),
+            FormulaCalc::Or(calcs) => join(f, calcs, "|"),
+            FormulaCalc::Not(calc) => {
+                if matches!(**calc, FormulaCalc::And(_) | FormulaCalc::Or(_)) {
+                    write!(f, "!({})", calc)
+                } else {
+                    write!(f, "!{}", calc)
+                }
+            }
+            FormulaCalc::Sw(v) => write!(f, "sw({})", v),
+            FormulaCalc::Ew(v) => write!(f, "ew({})", v),
+            FormulaCalc::Ct(v) => write!(f, "ct({})", v),
+            FormulaCalc::Len(v) => write!(f, "len({})", v),
+        }
+    }
+}
+
+/// Bounds of numbers a formula matches, used to check if formulas intersect
+struct Interval {
+    min: f64,
+    min_inclusive: bool,
+    max: f64,
+    max_inclusive: bool,
 }
 
 impl FormulaCalc {
@@ -124,25 +451,193 @@ impl FormulaCalc {
     where
         S: AsRef<str>,
     {
-        let Ok(value) = value.as_ref().parse::<i64>() else {
-            return matches!(self, FormulaCalc::Ne(_));
-        };
+        let value = value.as_ref();
+        let int = || value.parse::<Int>().ok();
+        let float = || value.parse::<f64>().ok();
         match self {
-            FormulaCalc::Eq(f) => value == *f,
-            FormulaCalc::Ne(f) => value != *f,
-            FormulaCalc::Gt(f) => value > *f,
-            FormulaCalc::Lt(f) => value < *f,
-            FormulaCalc::Ge(f) => value >= *f,
-            FormulaCalc::Le(f) => value <= *f,
-            FormulaCalc::Ri(f1, f2) => value >= *f1 && value <= *f2,
+            FormulaCalc::Eq(f) => int().is_some_and(|v| v == *f),
+            FormulaCalc::Ne(f) => int() != Some(*f),
+            FormulaCalc::Gt(f) => int().is_some_and(|v| v > *f),
+            FormulaCalc::Lt(f) => int().is_some_and(|v| v < *f),
+            FormulaCalc::Ge(f) => int().is_some_and(|v| v >= *f),
+            FormulaCalc::Le(f) => int().is_some_and(|v| v <= *f),
+            FormulaCalc::Ri(f1, f2) => int().is_some_and(|v| v >= *f1 && v <= *f2),
+            FormulaCalc::Rx(f1, f2) => int().is_some_and(|v| v >= *f1 && v < *f2),
+            FormulaCalc::GtF(f) => float().is_some_and(|v| v > f.0),
+            FormulaCalc::LtF(f) => float().is_some_and(|v| v < f.0),
+            FormulaCalc::GeF(f) => float().is_some_and(|v| v >= f.0),
+            FormulaCalc::LeF(f) => float().is_some_and(|v| v <= f.0),
+            FormulaCalc::RiF(f1, f2) => float().is_some_and(|v| v >= f1.0 && v <= f2.0),
+            FormulaCalc::Mod(n, r) => {
+                // the only overflowing case is Int::MIN % -1, which is zero
+                int().is_some_and(|v| v.checked_rem_euclid(*n).unwrap_or(0) == *r)
+            }
+            FormulaCalc::In(values) => int().is_some_and(|v| values.contains(&v)),
+            FormulaCalc::And(calcs) => calcs.iter().all(|calc| calc.matches(value)),
+            FormulaCalc::Or(calcs) => calcs.iter().any(|calc| calc.matches(value)),
+            FormulaCalc::Not(calc) => !calc.matches(value),
+            FormulaCalc::Sw(f) => value.starts_with(f.as_str()),
+            FormulaCalc::Ew(f) => value.ends_with(f.as_str()),
+            FormulaCalc::Ct(f) => value.contains(f.as_str()),
+            FormulaCalc::Len(f) => value.len() == *f,
+        }
+    }
+    /// Inclusive range of matching integers, None for `ne`, float functions or if nothing can
+    /// match
+    fn range(&self) -> Option<(Int, Int)> {
+        let (min, max) = match self {
+            FormulaCalc::Eq(f) => (*f, *f),
+            FormulaCalc::Gt(f) => (f.checked_add(1)?, Int::MAX),
+            FormulaCalc::Lt(f) => (Int::MIN, f.checked_sub(1)?),
+            FormulaCalc::Ge(f) => (*f, Int::MAX),
+            FormulaCalc::Le(f) => (Int::MIN, *f),
+            FormulaCalc::Ri(f1, f2) => (*f1, *f2),
+            FormulaCalc::Rx(f1, f2) => (*f1, f2.checked_sub(1)?),
+            _ => return None,
+        };
+        (min <= max).then_some((min, max))
+    }
+    /// Bounds of matching numbers for both integer and float functions, None for `ne`
+    #[allow(clippy::cast_precision_loss)]
+    fn interval(&self) -> Option<Interval> {
+        let bounds = |min: f64, min_inclusive: bool, max: f64, max_inclusive: bool| Interval {
+            min,
+            min_inclusive,
+            max,
+            max_inclusive,
+        };
+        let interval = match self {
+            FormulaCalc::Ne(_) => return None,
+            FormulaCalc::GtF(f) => bounds(f.0, false, f64::INFINITY, true),
+            FormulaCalc::LtF(f) => bounds(f64::NEG_INFINITY, true, f.0, false),
+            FormulaCalc::GeF(f) => bounds(f.0, true, f64::INFINITY, true),
+            FormulaCalc::LeF(f) => bounds(f64::NEG_INFINITY, true, f.0, true),
+            FormulaCalc::RiF(f1, f2) => bounds(f1.0, true, f2.0, true),
+            _ => {
+                let (min, max) = self.range()?;
+                bounds(min as f64, true, max as f64, true)
+            }
+        };
+        Some(interval)
+    }
+    /// Checks if the function matches a continuous range of numbers
+    fn is_range(&self) -> bool {
+        matches!(
+            self,
+            FormulaCalc::Eq(_)
+                | FormulaCalc::Gt(_)
+                | FormulaCalc::Lt(_)
+                | FormulaCalc::Ge(_)
+                | FormulaCalc::Le(_)
+                | FormulaCalc::Ri(..)
+                | FormulaCalc::Rx(..)
+                | FormulaCalc::GtF(_)
+                | FormulaCalc::LtF(_)
+                | FormulaCalc::GeF(_)
+                | FormulaCalc::LeF(_)
+                | FormulaCalc::RiF(..)
+        )
+    }
+    fn intersects(&self, other: &FormulaCalc) -> bool {
+        match (self, other) {
+            // both match any non-numeric value
+            (FormulaCalc::Ne(_), FormulaCalc::Ne(_)) => true,
+            (FormulaCalc::Eq(f), calc) | (calc, FormulaCalc::Eq(f)) => calc.matches(f.to_string()),
+            (FormulaCalc::In(values), calc) | (calc, FormulaCalc::In(values)) => {
+                values.iter().any(|f| calc.matches(f.to_string()))
+            }
+            (FormulaCalc::Ne(f), calc) | (calc, FormulaCalc::Ne(f)) => {
+                if !calc.is_range() {
+                    true
+                } else if let Some((min, max)) = calc.range() {
+                    min != *f || max != *f
+                } else {
+                    // float values with the fractional part are not integers
+                    calc.interval().is_some_and(|i| i.intersects(&i))
+                }
+            }
+            // other functions are assumed to intersect
+            (calc1, calc2) if !calc1.is_range() || !calc2.is_range() => true,
+            (calc1, calc2) => match (calc1.range(), calc2.range()) {
+                (Some((min1, max1)), Some((min2, max2))) => min1.max(min2) <= max1.min(max2),
+                // float functions or empty integer ranges
+                _ => calc1
+                    .interval()
+                    .is_some_and(|i1| calc2.interval().is_some_and(|i2| i1.intersects(&i2))),
+            },
         }
     }
 }
 
+impl Interval {
+    fn intersects(&self, other: &Interval) -> bool {
+        let (min, min_inclusive) = match self.min.total_cmp(&other.min) {
+            std::cmp::Ordering::Less => (other.min, other.min_inclusive),
+            std::cmp::Ordering::Greater => (self.min, self.min_inclusive),
+            std::cmp::Ordering::Equal => (self.min, self.min_inclusive && other.min_inclusive),
+        };
+        let (max, max_inclusive) = match self.max.total_cmp(&other.max) {
+            std::cmp::Ordering::Less => (self.max, self.max_inclusive),
+            std::cmp::Ordering::Greater => (other.max, other.max_inclusive),
+            std::cmp::Ordering::Equal => (self.max, self.max_inclusive && other.max_inclusive),
+        };
+        match min.total_cmp(&max) {
+            std::cmp::Ordering::Less => true,
+            std::cmp::Ordering::Equal => min_inclusive && max_inclusive,
+            std::cmp::Ordering::Greater => false,
+        }
+    }
+}
+
+/// Splits the formula by the operator, ignoring ones inside brackets
+fn split_top_level(s: &str, op: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0_usize;
+    let mut start = 0;
+    for (pos, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            c if c == op && depth == 0 => {
+                parts.push(&s[start..pos]);
+                start = pos + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
 impl FromStr for FormulaCalc {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let alternatives = split_top_level(s, '|');
+        if alternatives.len() > 1 {
+            return Ok(FormulaCalc::Or(
+                alternatives
+                    .into_iter()
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?,
+            ));
+        }
+        let conditions = split_top_level(s, '&');
+        if conditions.len() > 1 {
+            return Ok(FormulaCalc::And(
+                conditions
+                    .into_iter()
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?,
+            ));
+        }
+        let s = s.trim();
+        if let Some(negated) = s.strip_prefix('!') {
+            return Ok(FormulaCalc::Not(Box::new(negated.parse()?)));
+        }
+        if let Some(group) = s.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+            return group.parse();
+        }
         let mut parts = s.split('(');
         let kind = parts
             .next()
@@ -151,15 +646,13 @@ impl FromStr for FormulaCalc {
             .next()
             .ok_or_else(|| Error::FormulaParseError(format!("value not defined in {}", s)))?;
         let Some(value) = value.strip_suffix(')') else {
-            return Err(Error::FormulaParseError(format!(
-                "bracket not closed in {}",
-                s
-            )));
+            return Err(Error::UnclosedBracket(s.to_owned()));
         };
         macro_rules! parse_val {
             ($value:expr) => {
-                $value.parse().map_err(|e| {
-                    Error::FormulaParseError(format!("formula value parse error in {}: {}", s, e))
+                $value.parse().map_err(|e| Error::ValueParse {
+                    input: s.to_owned(),
+                    source: Box::new(e),
                 })?
             };
         }
@@ -170,20 +663,51 @@ impl FromStr for FormulaCalc {
             "lt" => Ok(FormulaCalc::Lt(parse_val!(value))),
             "ge" => Ok(FormulaCalc::Ge(parse_val!(value))),
             "le" => Ok(FormulaCalc::Le(parse_val!(value))),
-            "ri" => {
+            "gtf" => Ok(FormulaCalc::GtF(parse_val!(value))),
+            "ltf" => Ok(FormulaCalc::LtF(parse_val!(value))),
+            "gef" => Ok(FormulaCalc::GeF(parse_val!(value))),
+            "lef" => Ok(FormulaCalc::LeF(parse_val!(value))),
+            "sw" => Ok(FormulaCalc::Sw(value.to_owned())),
+            "ew" => Ok(FormulaCalc::Ew(value.to_owned())),
+            "ct" => Ok(FormulaCalc::Ct(value.to_owned())),
+            "len" => Ok(FormulaCalc::Len(parse_val!(value))),
+            "in" => {
+                let mut values = Vec::new();
+                for v in value.split('|') {
+                    values.push(parse_val!(v));
+                }
+                Ok(FormulaCalc::In(values))
+            }
+            "mod" => {
+                let mut parts = value.split(',');
+                let n: Int = parse_val!(parts.next().ok_or_else(|| {
+                    Error::FormulaParseError(format!("divisor not defined in {}", s))
+                })?);
+                let r = parse_val!(parts.next().ok_or_else(|| {
+                    Error::FormulaParseError(format!("remainder not defined in {}", s))
+                })?);
+                if n == 0 {
+                    return Err(Error::FormulaParseError(format!("zero divisor in {}", s)));
+                }
+                Ok(FormulaCalc::Mod(n, r))
+            }
+            "ri" | "rx" | "rif" => {
                 let mut parts = value.split("..");
-                let f1 = parse_val!(parts.next().ok_or_else(|| {
+                let v1 = parts.next().ok_or_else(|| {
                     Error::FormulaParseError(format!("range first value not defined in {}", s))
-                })?);
-                let f2 = parse_val!(parts.next().ok_or_else(|| {
+                })?;
+                let v2 = parts.next().ok_or_else(|| {
                     Error::FormulaParseError(format!("range second value not defined in {}", s))
-                })?);
-                Ok(FormulaCalc::Ri(f1, f2))
+                })?;
+                if kind == "ri" {
+                    Ok(FormulaCalc::Ri(parse_val!(v1), parse_val!(v2)))
+                } else if kind == "rx" {
+                    Ok(FormulaCalc::Rx(parse_val!(v1), parse_val!(v2)))
+                } else {
+                    Ok(FormulaCalc::RiF(parse_val!(v1), parse_val!(v2)))
+                }
             }
-            v => Err(Error::FormulaParseError(format!(
-                "unknown function in {}: {}",
-                s, v
-            ))),
+            v => Err(Error::UnknownFunction(v.to_owned())),
         }
     }
 }
@@ -253,7 +777,7 @@ where
     I: Iterator<Item = &'a K>,
 {
     let formula_parsed = formula.parse::<Formula>().ok();
-    keys.filter(move |key| formula_parsed.as_ref().map_or(false, |f| f.matches(key)))
+    keys.filter(move |key| formula_parsed.as_ref().is_some_and(|f| f.matches(key)))
 }
 
 fn values_match_key_formula<'a, K, V, I>(iter: I, formula: &str) -> impl Iterator<Item = &'a V>
@@ -263,7 +787,7 @@ where
     V: 'a,
 {
     let formula_parsed = formula.parse::<Formula>().ok();
-    iter.filter(move |(key, _)| formula_parsed.as_ref().map_or(false, |f| f.matches(key)))
+    iter.filter(move |(key, _)| formula_parsed.as_ref().is_some_and(|f| f.matches(key)))
         .map(|(_, value)| value)
 }
 
@@ -272,7 +796,7 @@ where
 mod tests {
     use std::collections::BTreeMap;
 
-    use super::MapKeysMatchFormula as _;
+    use super::{Formula, MapKeysMatchFormula as _};
 
     #[test]
     fn test_keys_matches_formula_eq() {
@@ -424,4 +948,423 @@ mod tests {
             .collect::<Vec<&String>>()
             .is_empty());
     }
+    #[test]
+    fn test_keys_matches_formula_float() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        h.insert("hello".to_string(), ());
+        h.insert("NaN".to_string(), ());
+        h.insert("22".to_string(), ());
+        h.insert("23".to_string(), ());
+        h.insert("23.5".to_string(), ());
+        h.insert("24.75".to_string(), ());
+        assert_eq!(
+            h.keys_match_formula("gtf(23.0)").collect::<Vec<&String>>(),
+            ["23.5", "24.75"]
+        );
+        assert_eq!(
+            h.keys_match_formula("lef(23.5)").collect::<Vec<&String>>(),
+            ["22", "23", "23.5"]
+        );
+        assert_eq!(
+            h.keys_match_formula("rif(22.5..24)")
+                .collect::<Vec<&String>>(),
+            ["23", "23.5"]
+        );
+        assert!(h
+            .keys_match_formula("gef(NaN)")
+            .collect::<Vec<&String>>()
+            .is_empty());
+        assert!(h
+            .keys_match_formula("gtf(x)")
+            .collect::<Vec<&String>>()
+            .is_empty());
+    }
+    #[test]
+    fn test_formula_intersects() {
+        for (a, b, expected) in [
+            ("gtf(1.5)", "ltf(1.5)", false),
+            ("gef(1.5)", "lef(1.5)", true),
+            ("rif(1..2)", "rif(2..3)", true),
+            ("gtf(1.5)", "le(1)", false),
+            ("gtf(1.5)", "eq(2)", true),
+            ("ltf(1.5)", "ne(1)", true),
+        ] {
+            let a: Formula = a.parse().unwrap();
+            let b: Formula = b.parse().unwrap();
+            assert_eq!(a.intersects(&b), expected, "{:?} {:?}", a, b);
+            assert_eq!(b.intersects(&a), expected, "{:?} {:?}", b, a);
+        }
+    }
+    #[test]
+    fn test_keys_matches_formula_mod() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in ["0", "5", "10", "20", "21", "-10", "x"] {
+            h.insert(key.to_string(), ());
+        }
+        assert_eq!(
+            h.keys_match_formula("mod(10,0)").collect::<Vec<&String>>(),
+            ["-10", "0", "10", "20"]
+        );
+        assert_eq!(
+            h.keys_match_formula("mod(10,1)").collect::<Vec<&String>>(),
+            ["21"]
+        );
+        assert!("mod(0,0)".parse::<Formula>().is_err());
+        assert!("mod(10)".parse::<Formula>().is_err());
+        let f: Formula = "mod(-1,0)".parse().unwrap();
+        assert!(f.matches(i64::MIN.to_string()));
+        let f: Formula = "mod(2,0)".parse().unwrap();
+        assert!(f.intersects(&"eq(4)".parse().unwrap()));
+        assert!(!f.intersects(&"eq(3)".parse().unwrap()));
+        assert!(f.intersects(&"gt(3)".parse().unwrap()));
+    }
+    #[test]
+    fn test_keys_matches_formula_in() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in ["1", "2", "3", "4", "5", "hello"] {
+            h.insert(key.to_string(), ());
+        }
+        assert_eq!(
+            h.keys_match_formula("in(1|2|5)").collect::<Vec<&String>>(),
+            ["1", "2", "5"]
+        );
+        assert!(h
+            .keys_match_formula("in(7|8)")
+            .collect::<Vec<&String>>()
+            .is_empty());
+        assert!("in(1|x)".parse::<Formula>().is_err());
+        assert!("in()".parse::<Formula>().is_err());
+        let f: Formula = "in(1|5)".parse().unwrap();
+        assert!(f.intersects(&"gt(4)".parse().unwrap()));
+        assert!(!f.intersects(&"ri(2..4)".parse().unwrap()));
+        assert!(f.intersects(&"in(5|6)".parse().unwrap()));
+    }
+    #[test]
+    fn test_keys_matches_formula_rx() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in ["1", "2", "3", "4", "5", "hello"] {
+            h.insert(key.to_string(), ());
+        }
+        assert_eq!(
+            h.keys_match_formula("rx(2..4)").collect::<Vec<&String>>(),
+            ["2", "3"]
+        );
+        assert!(h
+            .keys_match_formula("rx(4..2)")
+            .collect::<Vec<&String>>()
+            .is_empty());
+        assert!(h
+            .keys_match_formula("rx(2..2)")
+            .collect::<Vec<&String>>()
+            .is_empty());
+        let f: Formula = "rx(2..4)".parse().unwrap();
+        assert!(!f.intersects(&"ge(4)".parse().unwrap()));
+        assert!(f.intersects(&"ri(3..5)".parse().unwrap()));
+    }
+    #[test]
+    fn test_keys_matches_formula_and_or() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in ["0", "1", "5", "10", "15", "20", "hello"] {
+            h.insert(key.to_string(), ());
+        }
+        assert_eq!(
+            h.keys_match_formula("ge(10) & lt(20)")
+                .collect::<Vec<&String>>(),
+            ["10", "15"]
+        );
+        assert_eq!(
+            h.keys_match_formula("eq(1)|eq(5)")
+                .collect::<Vec<&String>>(),
+            ["1", "5"]
+        );
+        assert_eq!(
+            h.keys_match_formula("ge(10) & lt(20) | eq(0) | in(1|5) & gt(1)")
+                .collect::<Vec<&String>>(),
+            ["0", "10", "15", "5"]
+        );
+        assert!("ge(10) & ".parse::<Formula>().is_err());
+        assert!("ge(10) | xx(1)".parse::<Formula>().is_err());
+        let f: Formula = "a#gt(1) & lt(3)".parse().unwrap();
+        assert!(f.matches("a2"));
+        assert!(!f.matches("a3"));
+        assert!(f.intersects(&"a#eq(2)".parse().unwrap()));
+        assert!(!f.intersects(&"a#eq(3)".parse().unwrap()));
+    }
+    #[test]
+    fn test_keys_matches_formula_string() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in ["foo", "foobar", "barfoo", "xbazx", "dev_foo1", "dev_bar2"] {
+            h.insert(key.to_string(), ());
+        }
+        assert_eq!(
+            h.keys_match_formula("sw(foo)").collect::<Vec<&String>>(),
+            ["foo", "foobar"]
+        );
+        assert_eq!(
+            h.keys_match_formula("ew(foo)").collect::<Vec<&String>>(),
+            ["barfoo", "foo"]
+        );
+        assert_eq!(
+            h.keys_match_formula("ct(baz)").collect::<Vec<&String>>(),
+            ["xbazx"]
+        );
+        assert_eq!(
+            h.keys_match_formula("dev_#sw(foo)")
+                .collect::<Vec<&String>>(),
+            ["dev_foo1"]
+        );
+        assert_eq!(
+            h.keys_match_formula("sw(foo) | ew(2)")
+                .collect::<Vec<&String>>(),
+            ["dev_bar2", "foo", "foobar"]
+        );
+    }
+    #[test]
+    fn test_keys_matches_formula_not() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in ["1", "2", "3", "4", "5", "hello"] {
+            h.insert(key.to_string(), ());
+        }
+        assert_eq!(
+            h.keys_match_formula("!eq(5)").collect::<Vec<&String>>(),
+            ["1", "2", "3", "4", "hello"]
+        );
+        assert_eq!(
+            h.keys_match_formula("!ri(1..3)").collect::<Vec<&String>>(),
+            ["4", "5", "hello"]
+        );
+        assert_eq!(
+            h.keys_match_formula("!ne(2)").collect::<Vec<&String>>(),
+            ["2"]
+        );
+        assert_eq!(
+            h.keys_match_formula("!ri(1..3) & !sw(h)")
+                .collect::<Vec<&String>>(),
+            ["4", "5"]
+        );
+        assert!("!".parse::<Formula>().is_err());
+        assert!("!xx(1)".parse::<Formula>().is_err());
+        let f: Formula = "!eq(5)".parse().unwrap();
+        assert!(f.intersects(&"eq(4)".parse().unwrap()));
+        assert!(!f.intersects(&"eq(5)".parse().unwrap()));
+    }
+    #[test]
+    fn test_keys_matches_formula_u64() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in [
+            u64::MAX.to_string(),
+            (u64::MAX - 1).to_string(),
+            (i64::MAX as u64 + 1).to_string(),
+            i64::MIN.to_string(),
+            "1".to_owned(),
+        ] {
+            h.insert(key, ());
+        }
+        let max = u64::MAX.to_string();
+        let f = format!("ge({})", u64::MAX - 1);
+        assert_eq!(
+            h.keys_match_formula(&f).collect::<Vec<&String>>(),
+            [&(u64::MAX - 1).to_string(), &max]
+        );
+        let f = format!("gt({})", i64::MAX);
+        assert_eq!(h.keys_match_formula(&f).count(), 3);
+        let f = format!("eq({})", u64::MAX);
+        assert_eq!(h.keys_match_formula(&f).collect::<Vec<&String>>(), [&max]);
+        let f = format!("ri({}..{})", i64::MIN, u64::MAX);
+        assert_eq!(h.keys_match_formula(&f).count(), 5);
+        assert_eq!(h.keys_match_formula("mod(2,1)").count(), 2);
+        let f: Formula = "mod(-1,0)".parse().unwrap();
+        assert!(f.matches(i128::MIN.to_string()));
+        let f: Formula = "ne(1)".parse().unwrap();
+        assert!(f.matches(format!("{}0", u128::MAX)));
+        assert!(!"eq(1)"
+            .parse::<Formula>()
+            .unwrap()
+            .matches(format!("{}0", u128::MAX)));
+        assert!(format!("eq({}0)", u128::MAX).parse::<Formula>().is_err());
+    }
+    #[test]
+    fn test_keys_matches_formula_len() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in ["", "a", "12345678", "abcdefgh", "abcdefghi", "1234567"] {
+            h.insert(key.to_string(), ());
+        }
+        assert_eq!(
+            h.keys_match_formula("len(8)").collect::<Vec<&String>>(),
+            ["12345678", "abcdefgh"]
+        );
+        assert_eq!(
+            h.keys_match_formula("len(0)").collect::<Vec<&String>>(),
+            [""]
+        );
+        assert_eq!(
+            h.keys_match_formula("len(8) & gt(0)")
+                .collect::<Vec<&String>>(),
+            ["12345678"]
+        );
+        assert!(h.keys_match_formula("len(3)").next().is_none());
+        assert!("len(x)".parse::<Formula>().is_err());
+        assert!("len(-1)".parse::<Formula>().is_err());
+        let f: Formula = "len(2)".parse().unwrap();
+        assert!(f.intersects(&"eq(10)".parse().unwrap()));
+        assert!(!f.intersects(&"eq(1)".parse().unwrap()));
+    }
+    #[test]
+    fn test_formula_constructors() {
+        for (formula, s) in [
+            (Formula::eq(1), "eq(1)"),
+            (Formula::ne(1), "ne(1)"),
+            (Formula::gt(1), "gt(1)"),
+            (Formula::lt(1), "lt(1)"),
+            (Formula::ge(1), "ge(1)"),
+            (Formula::le(u64::MAX), "le(18446744073709551615)"),
+            (Formula::ri(1, 3), "ri(1..3)"),
+            (Formula::rx(1, 3), "rx(1..3)"),
+            (Formula::gtf(1.5), "gtf(1.5)"),
+            (Formula::ltf(1.5), "ltf(1.5)"),
+            (Formula::gef(1.5), "gef(1.5)"),
+            (Formula::lef(1.5), "lef(1.5)"),
+            (Formula::rif(1.5, 2.5), "rif(1.5..2.5)"),
+            (Formula::modulo(3, 1), "mod(3,1)"),
+            (Formula::any_of([1, 2, 5]), "in(1|2|5)"),
+            (Formula::sw("a"), "sw(a)"),
+            (Formula::ew("a"), "ew(a)"),
+            (Formula::ct("a"), "ct(a)"),
+            (Formula::len(8), "len(8)"),
+            (!Formula::eq(5), "!eq(5)"),
+            (Formula::ge(4).with_prefix("a"), "a#ge(4)"),
+            (!Formula::ge(4).with_prefix("a"), "a#!ge(4)"),
+        ] {
+            assert_eq!(formula, s.parse().unwrap(), "{}", s);
+        }
+    }
+    #[test]
+    fn test_formula_display() {
+        for s in [
+            "eq(1)",
+            "ne(-1)",
+            "gt(1)",
+            "lt(1)",
+            "ge(18446744073709551615)",
+            "a#ge(4)",
+            "le(1)",
+            "ri(2..4)",
+            "rx(2..4)",
+            "gtf(1.5)",
+            "ltf(-1.5)",
+            "gef(1)",
+            "lef(NaN)",
+            "rif(22.5..24)",
+            "mod(3,1)",
+            "in(1|2|5)",
+            "sw(foo)",
+            "ew(foo)",
+            "ct(foo)",
+            "dev_#sw(foo)",
+            "len(8)",
+            "!ri(1..3)",
+            "ge(10)&lt(20)|eq(0)|in(1|5)&!eq(1)",
+            "!(ge(1)&lt(3))",
+            "!(eq(1)|eq(2))&ge(0)",
+            "ge(0)&(lt(10)|gt(20))",
+            "!!eq(1)",
+        ] {
+            let formula: Formula = s.parse().unwrap();
+            assert_eq!(formula.to_string(), s);
+            assert_eq!(formula.to_string().parse::<Formula>().unwrap(), formula);
+        }
+        let formula: Formula = "ge(10) & lt(20)".parse().unwrap();
+        assert_eq!(formula.to_string(), "ge(10)&lt(20)");
+        let formula = !"ge(1)&lt(3)".parse::<Formula>().unwrap();
+        assert_eq!(formula.to_string(), "!(ge(1)&lt(3))");
+        assert_eq!(formula.to_string().parse::<Formula>().unwrap(), formula);
+        assert!(formula.matches("0"));
+        assert!(!formula.matches("2"));
+        assert!(formula.matches("3"));
+    }
+    #[test]
+    fn test_keys_matches_formula_sorted() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in ["10", "2", "1", "-5", "100", "x", "a"] {
+            h.insert(key.to_string(), ());
+        }
+        assert_eq!(
+            h.keys_match_formula("ge(1)").collect::<Vec<&String>>(),
+            ["1", "10", "100", "2"]
+        );
+        assert_eq!(
+            h.keys_match_formula_sorted("ge(1)"),
+            ["1", "2", "10", "100"]
+        );
+        assert_eq!(
+            h.keys_match_formula_sorted("ne(2)"),
+            ["-5", "1", "10", "100", "a", "x"]
+        );
+        let h: std::collections::HashMap<String, ()> =
+            h.into_iter().filter(|(k, ())| k != "x").collect();
+        assert_eq!(
+            h.keys_match_formula_sorted("ne(1)"),
+            ["-5", "2", "10", "100", "a"]
+        );
+    }
+    #[test]
+    fn test_count_keys_match_formula() {
+        let keys = ["1", "2", "3", "4", "5", "hello"];
+        let b: BTreeMap<String, ()> = keys.iter().map(|k| ((*k).to_owned(), ())).collect();
+        let h: std::collections::HashMap<String, ()> =
+            keys.iter().map(|k| ((*k).to_owned(), ())).collect();
+        for (formula, expected) in [("ge(3)", 3), ("ne(1)", 5), ("sw(h)", 1), ("xx(1)", 0)] {
+            assert_eq!(b.count_keys_match_formula(formula), expected, "{}", formula);
+            assert_eq!(h.count_keys_match_formula(formula), expected, "{}", formula);
+        }
+    }
+    #[test]
+    fn test_try_keys_match_formula() {
+        let mut h: BTreeMap<String, ()> = BTreeMap::new();
+        for key in ["1", "2", "3", "hello"] {
+            h.insert(key.to_string(), ());
+        }
+        assert!(matches!(
+            h.try_keys_match_formula("xxx(2)"),
+            Err(crate::Error::UnknownFunction(_))
+        ));
+        assert_eq!(
+            h.try_keys_match_formula("ge(2)")
+                .unwrap()
+                .collect::<Vec<&String>>(),
+            ["2", "3"]
+        );
+        assert!(h.try_keys_match_formula("ge(5)").unwrap().next().is_none());
+    }
+    #[test]
+    fn test_formula_parse_errors() {
+        use crate::Error;
+        use std::error::Error as _;
+        assert!(matches!(
+            "xxx(2)".parse::<Formula>(),
+            Err(Error::UnknownFunction(f)) if f == "xxx"
+        ));
+        assert!(matches!(
+            "a#ge(2".parse::<Formula>(),
+            Err(Error::UnclosedBracket(s)) if s == "ge(2"
+        ));
+        let err = "ge(x)".parse::<Formula>().unwrap_err();
+        assert!(matches!(err, Error::ValueParse { ref input, .. } if input == "ge(x)"));
+        assert!(err.source().unwrap().is::<std::num::ParseIntError>());
+        assert_eq!(
+            err.to_string(),
+            "formula value parse error in ge(x): invalid digit found in string"
+        );
+        let err = "gtf(x)".parse::<Formula>().unwrap_err();
+        assert!(err.source().unwrap().is::<std::num::ParseFloatError>());
+        assert!(matches!(
+            "mod(0,0)".parse::<Formula>(),
+            Err(Error::FormulaParseError(_))
+        ));
+        assert!(matches!(
+            Formula::try_modulo(0, 1),
+            Err(Error::FormulaParseError(_))
+        ));
+        assert_eq!(Formula::try_modulo(3, 1).unwrap(), Formula::modulo(3, 1));
+    }
 }
@@ -1,44 +1,261 @@
-use openssl::sha::{Sha256, Sha512};
+use openssl::sha::{Sha256 as Sha256Hasher, Sha512 as Sha512Hasher};
+
+use crate::Error;
+use std::fmt::Write as _;
 
 #[allow(clippy::module_name_repetitions)]
 pub type Sha256Digest = [u64; 4];
 #[allow(clippy::module_name_repetitions)]
 pub type Sha512Digest = [u64; 8];
+#[cfg(feature = "blake3")]
+#[allow(clippy::module_name_repetitions)]
+pub type Blake3Digest = [u64; 4];
+
+/// Digest algorithm which produces fixed-size u64 arrays, can be used to make generic code
+/// parameterized over the hash algorithm
+pub trait TopicDigest {
+    type Output;
+    fn digest(data: impl AsRef<[u8]>) -> Self::Output;
+}
+
+/// SHA-256 algorithm
+pub struct Sha256;
+
+impl TopicDigest for Sha256 {
+    type Output = Sha256Digest;
+    #[inline]
+    fn digest(data: impl AsRef<[u8]>) -> Self::Output {
+        let mut stream = Sha256Stream::new();
+        stream.update(data);
+        stream.finalize()
+    }
+}
+
+/// Streaming SHA-256 calculation for payloads assembled from chunks, produces the same result as
+/// [`sha256`]
+pub struct Sha256Stream {
+    hasher: Sha256Hasher,
+}
+
+impl Default for Sha256Stream {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256Stream {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            hasher: Sha256Hasher::new(),
+        }
+    }
+    #[inline]
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        self.hasher.update(data.as_ref());
+    }
+    /// Finishes the calculation and returns the digest
+    ///
+    /// # Panics
+    ///
+    /// Should not panic
+    pub fn finalize(self) -> Sha256Digest {
+        let hash_arr: [u8; 32] = self.hasher.finish();
+        let hash_1 = u64::from_le_bytes(hash_arr[..8].try_into().unwrap());
+        let hash_2 = u64::from_le_bytes(hash_arr[8..16].try_into().unwrap());
+        let hash_3 = u64::from_le_bytes(hash_arr[16..24].try_into().unwrap());
+        let hash_4 = u64::from_le_bytes(hash_arr[24..].try_into().unwrap());
+        [hash_1, hash_2, hash_3, hash_4]
+    }
+}
+
+/// SHA-512 algorithm
+pub struct Sha512;
+
+impl TopicDigest for Sha512 {
+    type Output = Sha512Digest;
+    #[inline]
+    fn digest(data: impl AsRef<[u8]>) -> Self::Output {
+        let mut stream = Sha512Stream::new();
+        stream.update(data);
+        stream.finalize()
+    }
+}
+
+/// Streaming SHA-512 calculation for payloads assembled from chunks, produces the same result as
+/// [`sha512`]
+pub struct Sha512Stream {
+    hasher: Sha512Hasher,
+}
+
+impl Default for Sha512Stream {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha512Stream {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            hasher: Sha512Hasher::new(),
+        }
+    }
+    #[inline]
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        self.hasher.update(data.as_ref());
+    }
+    /// Finishes the calculation and returns the digest
+    ///
+    /// # Panics
+    ///
+    /// Should not panic
+    pub fn finalize(self) -> Sha512Digest {
+        let hash_arr: [u8; 64] = self.hasher.finish();
+        let hash_1 = u64::from_le_bytes(hash_arr[..8].try_into().unwrap());
+        let hash_2 = u64::from_le_bytes(hash_arr[8..16].try_into().unwrap());
+        let hash_3 = u64::from_le_bytes(hash_arr[16..24].try_into().unwrap());
+        let hash_4 = u64::from_le_bytes(hash_arr[24..32].try_into().unwrap());
+        let hash_5 = u64::from_le_bytes(hash_arr[32..40].try_into().unwrap());
+        let hash_6 = u64::from_le_bytes(hash_arr[40..48].try_into().unwrap());
+        let hash_7 = u64::from_le_bytes(hash_arr[48..56].try_into().unwrap());
+        let hash_8 = u64::from_le_bytes(hash_arr[56..].try_into().unwrap());
+        [
+            hash_1, hash_2, hash_3, hash_4, hash_5, hash_6, hash_7, hash_8,
+        ]
+    }
+}
+
+/// BLAKE3 algorithm
+#[cfg(feature = "blake3")]
+pub struct Blake3;
+
+#[cfg(feature = "blake3")]
+impl TopicDigest for Blake3 {
+    type Output = Blake3Digest;
+    #[inline]
+    fn digest(data: impl AsRef<[u8]>) -> Self::Output {
+        let hash_arr: [u8; 32] = blake3::hash(data.as_ref()).into();
+        let hash_1 = u64::from_le_bytes(hash_arr[..8].try_into().unwrap());
+        let hash_2 = u64::from_le_bytes(hash_arr[8..16].try_into().unwrap());
+        let hash_3 = u64::from_le_bytes(hash_arr[16..24].try_into().unwrap());
+        let hash_4 = u64::from_le_bytes(hash_arr[24..].try_into().unwrap());
+        [hash_1, hash_2, hash_3, hash_4]
+    }
+}
 
 /// A helper function to quickly calculate sha256 hash as [u64; 4]
-///
-/// # Panics
-///
-/// Should not panic
+#[inline]
 pub fn sha256(data: impl AsRef<[u8]>) -> Sha256Digest {
-    let mut hasher = Sha256::new();
-    hasher.update(data.as_ref());
-    let hash_arr: [u8; 32] = hasher.finish();
-    let hash_1 = u64::from_le_bytes(hash_arr[..8].try_into().unwrap());
-    let hash_2 = u64::from_le_bytes(hash_arr[8..16].try_into().unwrap());
-    let hash_3 = u64::from_le_bytes(hash_arr[16..24].try_into().unwrap());
-    let hash_4 = u64::from_le_bytes(hash_arr[24..].try_into().unwrap());
-    [hash_1, hash_2, hash_3, hash_4]
+    Sha256::digest(data)
 }
 
 /// A helper function to quickly calculate sha512 hash as [u64; 8]
-///
-/// # Panics
-///
-/// Should not panic
+#[inline]
 pub fn sha512(data: impl AsRef<[u8]>) -> Sha512Digest {
-    let mut hasher = Sha512::new();
-    hasher.update(data.as_ref());
-    let hash_arr: [u8; 64] = hasher.finish();
-    let hash_1 = u64::from_le_bytes(hash_arr[..8].try_into().unwrap());
-    let hash_2 = u64::from_le_bytes(hash_arr[8..16].try_into().unwrap());
-    let hash_3 = u64::from_le_bytes(hash_arr[16..24].try_into().unwrap());
-    let hash_4 = u64::from_le_bytes(hash_arr[24..32].try_into().unwrap());
-    let hash_5 = u64::from_le_bytes(hash_arr[32..40].try_into().unwrap());
-    let hash_6 = u64::from_le_bytes(hash_arr[40..48].try_into().unwrap());
-    let hash_7 = u64::from_le_bytes(hash_arr[48..56].try_into().unwrap());
-    let hash_8 = u64::from_le_bytes(hash_arr[56..].try_into().unwrap());
-    [
-        hash_1, hash_2, hash_3, hash_4, hash_5, hash_6, hash_7, hash_8,
-    ]
+    Sha512::digest(data)
+}
+
+/// A helper function to quickly calculate blake3 hash as [u64; 4]
+#[cfg(feature = "blake3")]
+#[inline]
+pub fn blake3(data: impl AsRef<[u8]>) -> Blake3Digest {
+    Blake3::digest(data)
+}
+
+/// Converts a digest to a lowercase hex string, keeping the original byte order of the hash
+pub fn to_hex(d: &[u64]) -> String {
+    let mut result = String::with_capacity(d.len() * 16);
+    for v in d {
+        for b in v.to_le_bytes() {
+            write!(result, "{:02x}", b).unwrap();
+        }
+    }
+    result
+}
+
+/// Converts a hex string back to a digest
+pub fn from_hex(s: &str) -> Result<Vec<u64>, Error> {
+    let mut result = Vec::with_capacity(s.len() / 16);
+    for chunk in s.as_bytes().chunks(16) {
+        if chunk.len() < 16 {
+            return Err(Error::HexParseError(format!("invalid length: {}", s.len())));
+        }
+        let mut bytes = [0u8; 8];
+        for (b, pair) in bytes.iter_mut().zip(chunk.chunks(2)) {
+            *b = std::str::from_utf8(pair)
+                .ok()
+                .and_then(|v| u8::from_str_radix(v, 16).ok())
+                .ok_or_else(|| Error::HexParseError(s.to_owned()))?;
+        }
+        result.push(u64::from_le_bytes(bytes));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        from_hex, sha256, sha512, to_hex, Sha256, Sha256Stream, Sha512, Sha512Stream, TopicDigest,
+    };
+
+    fn fingerprint<D: TopicDigest>(topic: &str) -> D::Output {
+        D::digest(topic)
+    }
+
+    #[test]
+    fn test_topic_digest() {
+        assert_eq!(
+            fingerprint::<Sha256>("unit/tests/t1"),
+            sha256("unit/tests/t1")
+        );
+        assert_eq!(
+            fingerprint::<Sha512>("unit/tests/t1"),
+            sha512("unit/tests/t1")
+        );
+        assert_ne!(sha256("unit/tests/t1"), sha256("unit/tests/t2"));
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_blake3() {
+        use super::{blake3, Blake3};
+        let digest = fingerprint::<Blake3>("unit/tests/t1");
+        assert_eq!(digest, blake3("unit/tests/t1"));
+        assert_eq!(
+            digest[0].to_le_bytes(),
+            ::blake3::hash(b"unit/tests/t1").as_bytes()[..8]
+        );
+        assert_ne!(digest, sha256("unit/tests/t1"));
+    }
+
+    #[test]
+    fn test_hex() {
+        let digest = sha256(b"");
+        let hex = to_hex(&digest);
+        assert_eq!(
+            hex,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(from_hex(&hex).unwrap(), digest);
+        let digest = sha512("unit/tests/t1");
+        assert_eq!(from_hex(&to_hex(&digest)).unwrap(), digest);
+        assert!(from_hex("e3b0").is_err());
+        assert!(from_hex("e3b0c44298fc1c1x").is_err());
+        assert!(from_hex("e3b0c44298fc1c1ж").is_err());
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut stream = Sha256Stream::new();
+        stream.update("unit/tests/");
+        stream.update(b"t1");
+        assert_eq!(stream.finalize(), sha256("unit/tests/t1"));
+        let mut stream = Sha512Stream::default();
+        stream.update("unit/tests/");
+        stream.update(b"t1");
+        assert_eq!(stream.finalize(), sha512("unit/tests/t1"));
+    }
 }
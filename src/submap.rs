@@ -1,8 +1,14 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
 use std::str::Split;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
 
 use crate::mkmf::{Formula, MapKeysMatchFormula as _};
 #[allow(clippy::wildcard_imports)]
 use crate::types::*;
+use crate::Error;
 
 #[derive(Debug, Clone)]
 struct RegexSubscription<C> {
@@ -16,8 +22,11 @@ struct Subscription<C> {
     subtopics: Map<String, Subscription<C>>,
     subtopics_by_formula: Map<Formula, Subscription<C>>,
     subtopics_by_regex: Vec<RegexSubscription<C>>,
-    subtopics_any: Option<Box<Subscription<C>>>, // ?
-    sub_any: Set<C>,                             // *
+    subtopics_any: Option<Box<Subscription<C>>>,      // ?
+    subtopics_levels: Map<usize, Subscription<C>>,    // tokens matching N levels
+    subtopics_wildcard: Option<Box<Subscription<C>>>, // */x (leading wildcards only)
+    sub_any: Set<C>,                                  // *
+    sub_any_zero: Set<C>,                             // zero-or-more levels wildcard
 }
 
 impl<C> Default for Subscription<C> {
@@ -28,7 +37,10 @@ impl<C> Default for Subscription<C> {
             subtopics_by_formula: <_>::default(),
             subtopics_by_regex: <_>::default(),
             subtopics_any: None,
+            subtopics_levels: <_>::default(),
+            subtopics_wildcard: None,
             sub_any: <_>::default(),
+            sub_any_zero: <_>::default(),
         }
     }
 }
@@ -41,33 +53,752 @@ impl<C> Subscription<C> {
             && self.subtopics_by_formula.is_empty()
             && self.subtopics_by_regex.is_empty()
             && self.subtopics_any.is_none()
+            && self.subtopics_levels.is_empty()
+            && self.subtopics_wildcard.is_none()
             && self.sub_any.is_empty()
+            && self.sub_any_zero.is_empty()
+    }
+    fn node_count(&self) -> usize {
+        1 + self.subtopics.values().map(Self::node_count).sum::<usize>()
+            + self
+                .subtopics_by_formula
+                .values()
+                .map(Self::node_count)
+                .sum::<usize>()
+            + self
+                .subtopics_by_regex
+                .iter()
+                .map(|rs| rs.sub.node_count())
+                .sum::<usize>()
+            + self
+                .subtopics_any
+                .as_ref()
+                .map_or(0, |sub| sub.node_count())
+            + self
+                .subtopics_levels
+                .values()
+                .map(Self::node_count)
+                .sum::<usize>()
+            + self
+                .subtopics_wildcard
+                .as_ref()
+                .map_or(0, |sub| sub.node_count())
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct SubMap<C> {
+impl<C> Subscription<C>
+where
+    C: Client,
+{
+    fn shrink_to_fit(&mut self) {
+        self.subscribers.shrink_to_fit();
+        self.sub_any.shrink_to_fit();
+        self.sub_any_zero.shrink_to_fit();
+        self.subtopics.shrink_to_fit();
+        for sub in self.subtopics.values_mut() {
+            sub.shrink_to_fit();
+        }
+        self.subtopics_by_formula.shrink_to_fit();
+        for sub in self.subtopics_by_formula.values_mut() {
+            sub.shrink_to_fit();
+        }
+        self.subtopics_by_regex.shrink_to_fit();
+        for rs in &mut self.subtopics_by_regex {
+            rs.sub.shrink_to_fit();
+        }
+        if let Some(ref mut sub) = self.subtopics_any {
+            sub.shrink_to_fit();
+        }
+        self.subtopics_levels.shrink_to_fit();
+        for sub in self.subtopics_levels.values_mut() {
+            sub.shrink_to_fit();
+        }
+        if let Some(ref mut sub) = self.subtopics_wildcard {
+            sub.shrink_to_fit();
+        }
+    }
+}
+
+/// Shared subscription group, a message is delivered to one group member only. As in MQTT 5,
+/// groups are identified by both the name and the topic filter, so the map keys are whole
+/// (normalized) shared subscription topics
+#[derive(Debug)]
+struct SharedGroup<C> {
     subscriptions: Subscription<C>,
-    subscribed_topics: Map<C, Set<String>>,
-    subscription_count: usize,
-    separator: char,
+    next: AtomicUsize,
+}
+
+impl<C> Default for SharedGroup<C> {
+    fn default() -> Self {
+        Self {
+            subscriptions: <_>::default(),
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<C> Clone for SharedGroup<C>
+where
+    C: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            subscriptions: self.subscriptions.clone(),
+            next: AtomicUsize::new(self.next.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Default max number of subscription topic levels
+const DEFAULT_MAX_DEPTH: usize = 1024;
+
+/// Max number of compiled query regular expressions to keep, the cache is cleared when exceeded
+const REGEX_CACHE_SIZE: usize = 1000;
+
+#[derive(Debug, Default)]
+struct RegexCache {
+    regexes: Mutex<HashMap<String, regex::Regex>>,
+}
+
+impl Clone for RegexCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl RegexCache {
+    fn get(&self, regex: &str) -> Option<regex::Regex> {
+        let mut regexes = self.regexes.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(compiled) = regexes.get(regex) {
+            return Some(compiled.clone());
+        }
+        let compiled = regex::Regex::new(regex).ok()?;
+        if regexes.len() >= REGEX_CACHE_SIZE {
+            regexes.clear();
+        }
+        regexes.insert(regex.to_owned(), compiled.clone());
+        Some(compiled)
+    }
+    fn clear(&self) {
+        self.regexes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Separator {
+    Char(char),
+    Str(String),
+    Chars(Vec<char>),
+}
+
+impl Separator {
+    /// # Panics
+    ///
+    /// Will panic if the separator is empty
+    pub(crate) fn new(separator: &str) -> Self {
+        assert!(!separator.is_empty(), "separator can not be empty");
+        let mut chars = separator.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Separator::Char(c),
+            _ => Separator::Str(separator.to_owned()),
+        }
+    }
+    /// # Panics
+    ///
+    /// Will panic if the separator list is empty
+    pub(crate) fn any_of(separators: &[char]) -> Self {
+        match separators {
+            [] => panic!("separator can not be empty"),
+            [c] => Separator::Char(*c),
+            _ => Separator::Chars(separators.to_vec()),
+        }
+    }
+    #[inline]
+    pub(crate) fn split<'a>(&'a self, topic: &'a str) -> TopicSplit<'a> {
+        match self {
+            Separator::Char(c) => TopicSplit::Char(topic.split(*c)),
+            Separator::Str(s) => TopicSplit::Str(topic.split(s.as_str())),
+            Separator::Chars(chars) => TopicSplit::Chars(topic.split(chars.as_slice())),
+        }
+    }
+    /// Appends the separator, the first one of multiple separators is used
+    fn push_to(&self, s: &mut String) {
+        match self {
+            Separator::Char(c) => s.push(*c),
+            Separator::Str(sep) => s.push_str(sep),
+            Separator::Chars(chars) => s.push(chars[0]),
+        }
+    }
+    /// Returns true if the string contains the separator
+    fn is_in(&self, s: &str) -> bool {
+        match self {
+            Separator::Char(c) => s.contains(*c),
+            Separator::Str(sep) => s.contains(sep.as_str()),
+            Separator::Chars(chars) => s.contains(chars.as_slice()),
+        }
+    }
+    /// Returns the position and the length of the first separator, which is not preceded by the
+    /// escape char (escape chars can be escaped as well)
+    fn find_unescaped(&self, topic: &str, escape: char) -> Option<(usize, usize)> {
+        let escaped = |pos: usize| {
+            topic[..pos]
+                .chars()
+                .rev()
+                .take_while(|c| *c == escape)
+                .count()
+                % 2
+                == 1
+        };
+        match self {
+            Separator::Char(c) => topic
+                .match_indices(*c)
+                .map(|(pos, _)| (pos, c.len_utf8()))
+                .find(|(pos, _)| !escaped(*pos)),
+            Separator::Str(s) => topic
+                .match_indices(s.as_str())
+                .map(|(pos, _)| (pos, s.len()))
+                .find(|(pos, _)| !escaped(*pos)),
+            Separator::Chars(chars) => topic
+                .match_indices(chars.as_slice())
+                .map(|(pos, m)| (pos, m.len()))
+                .find(|(pos, _)| !escaped(*pos)),
+        }
+    }
+}
+
+impl std::fmt::Display for Separator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Separator::Char(c) => write!(f, "{}", c),
+            Separator::Str(s) => write!(f, "{}", s),
+            Separator::Chars(chars) => {
+                for c in chars {
+                    write!(f, "{}", c)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum TopicSplit<'a> {
+    Char(Split<'a, char>),
+    Str(Split<'a, &'a str>),
+    Chars(Split<'a, &'a [char]>),
+    Escaped {
+        rest: Option<&'a str>,
+        separator: &'a Separator,
+        escape: char,
+    },
+}
+
+impl<'a> Iterator for TopicSplit<'a> {
+    type Item = &'a str;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            TopicSplit::Char(sp) => sp.next(),
+            TopicSplit::Str(sp) => sp.next(),
+            TopicSplit::Chars(sp) => sp.next(),
+            TopicSplit::Escaped {
+                rest,
+                separator,
+                escape,
+            } => {
+                let topic = (*rest)?;
+                if let Some((pos, len)) = separator.find_unescaped(topic, *escape) {
+                    *rest = Some(&topic[pos + len..]);
+                    Some(&topic[..pos])
+                } else {
+                    *rest = None;
+                    Some(topic)
+                }
+            }
+        }
+    }
+}
+
+/// A parsed subscription pattern chunk, used to compare patterns
+enum PatternToken<'a> {
+    Literal(Cow<'a, str>),
+    Any,
+    Formula(Formula),
+    Regex(regex::Regex),
+    // a non-terminal wildcard (zero or more chunks)
+    Star,
+    // a terminal wildcard
+    Wildcard,
+    // a terminal zero-or-more levels wildcard
+    WildcardZero,
+}
+
+impl PatternToken<'_> {
+    fn is_single(&self) -> bool {
+        !matches!(
+            self,
+            PatternToken::Star | PatternToken::Wildcard | PatternToken::WildcardZero
+        )
+    }
+    /// Checks if a top-level wildcard or match-any token can not match the chunk
+    fn is_reserved(&self, settings: &Settings) -> bool {
+        matches!(self, PatternToken::Literal(s) if settings.is_reserved(Some(s)))
+    }
+    /// Checks if the single-chunk token matches every chunk the other one matches
+    fn covers(&self, other: &PatternToken, settings: &Settings) -> bool {
+        match (self, other) {
+            (PatternToken::Any, PatternToken::Any) => true,
+            (PatternToken::Any, PatternToken::Literal(s)) => {
+                !s.is_empty() || (settings.match_any_empty && !settings.strict_levels)
+            }
+            (PatternToken::Any, PatternToken::Formula(_) | PatternToken::Regex(_)) => {
+                settings.match_any_empty && !settings.strict_levels
+            }
+            (PatternToken::Literal(a), PatternToken::Literal(b)) => a == b,
+            (PatternToken::Formula(f), PatternToken::Literal(s)) => f.matches(s),
+            (PatternToken::Regex(r), PatternToken::Literal(s)) => r.is_match(s),
+            (PatternToken::Formula(a), PatternToken::Formula(b)) => a == b,
+            (PatternToken::Regex(a), PatternToken::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+    /// Checks if the token may match a reserved chunk
+    fn may_be_reserved(&self, settings: &Settings) -> bool {
+        match self {
+            PatternToken::Literal(s) => settings.is_reserved(Some(s)),
+            PatternToken::Formula(_) | PatternToken::Regex(_) => settings.reserved_prefix.is_some(),
+            _ => false,
+        }
+    }
+    /// Checks if there is a topic chunk both single-chunk tokens match
+    fn intersects(&self, other: &PatternToken, settings: &Settings) -> bool {
+        match (self, other) {
+            (PatternToken::Literal(a), PatternToken::Literal(b)) => a == b,
+            (PatternToken::Literal(s), PatternToken::Any)
+            | (PatternToken::Any, PatternToken::Literal(s)) => {
+                settings.match_any_empty || !s.is_empty()
+            }
+            (PatternToken::Literal(s), PatternToken::Formula(f))
+            | (PatternToken::Formula(f), PatternToken::Literal(s)) => f.matches(s),
+            (PatternToken::Literal(s), PatternToken::Regex(r))
+            | (PatternToken::Regex(r), PatternToken::Literal(s)) => r.is_match(s),
+            (PatternToken::Formula(a), PatternToken::Formula(b)) => a.intersects(b),
+            _ => true,
+        }
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone)]
+struct Settings {
+    separator: Separator,
+    escape_char: Option<char>,
     formula_prefix: Option<String>,
     regex_prefix: Option<String>,
     match_any: Set<String>,
+    match_levels: Map<String, usize>,
     wildcard: Set<String>,
+    wildcard_zero_plus: Set<String>,
+    match_any_empty: bool,
+    leading_wildcard: bool,
+    wildcard_matches_parent: bool,
+    strict_levels: bool,
+    terminal_wildcard: bool,
+    case_insensitive: bool,
+    reserved_prefix: Option<String>,
+    share_prefix: Option<String>,
+    max_depth: usize,
+    regex_cache: RegexCache,
 }
 
-impl<C> Default for SubMap<C> {
+impl Default for Settings {
     fn default() -> Self {
         Self {
-            subscriptions: <_>::default(),
-            subscribed_topics: <_>::default(),
-            subscription_count: 0,
-            separator: '/',
+            separator: Separator::Char('/'),
+            escape_char: None,
             formula_prefix: None,
             regex_prefix: None,
             match_any: vec!["?".to_owned()].into_iter().collect(),
+            match_levels: Map::default(),
             wildcard: vec!["*".to_owned()].into_iter().collect(),
+            wildcard_zero_plus: Set::default(),
+            match_any_empty: true,
+            leading_wildcard: false,
+            wildcard_matches_parent: false,
+            strict_levels: false,
+            terminal_wildcard: false,
+            case_insensitive: false,
+            reserved_prefix: None,
+            share_prefix: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            regex_cache: <_>::default(),
+        }
+    }
+}
+
+impl Settings {
+    #[inline]
+    fn split<'a>(&'a self, topic: &'a str) -> TopicSplit<'a> {
+        if let Some(escape) = self.escape_char {
+            return TopicSplit::Escaped {
+                rest: Some(topic),
+                separator: &self.separator,
+                escape,
+            };
+        }
+        self.separator.split(topic)
+    }
+    /// Normalizes a literal topic chunk
+    #[inline]
+    fn chunk<'a>(&self, chunk: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            Cow::Owned(chunk.to_lowercase())
+        } else {
+            Cow::Borrowed(chunk)
+        }
+    }
+    /// Returns the topic as it is stored in the client index: literal chunks are normalized and
+    /// joined with the primary separator, so different spellings of the same subscription (e.g.
+    /// "Sensors/Temp" and "sensors/temp" if case-insensitive) are stored once
+    fn normalize<'a>(&self, topic: &'a str) -> Cow<'a, str> {
+        if !self.case_insensitive && !matches!(self.separator, Separator::Chars(_)) {
+            return Cow::Borrowed(topic);
+        }
+        let mut result = String::with_capacity(topic.len());
+        let filter = if let Some((group, filter)) = self.parse_shared(topic) {
+            if let Some(ref prefix) = self.share_prefix {
+                result.push_str(prefix);
+            }
+            self.separator.push_to(&mut result);
+            result.push_str(group);
+            self.separator.push_to(&mut result);
+            filter
+        } else {
+            topic
+        };
+        for (i, chunk) in self.split(filter).enumerate() {
+            if i > 0 {
+                self.separator.push_to(&mut result);
+            }
+            if self.is_token(chunk) {
+                result.push_str(chunk);
+            } else {
+                result.push_str(&self.chunk(chunk));
+            }
+        }
+        Cow::Owned(result)
+    }
+    /// Checks if the chunk is a wildcard/match-any token, a formula or a regex
+    fn is_token(&self, chunk: &str) -> bool {
+        self.wildcard.contains(chunk)
+            || self.wildcard_zero_plus.contains(chunk)
+            || self.match_any.contains(chunk)
+            || self.match_levels.contains_key(chunk)
+            || self
+                .formula_prefix
+                .as_deref()
+                .is_some_and(|p| chunk.starts_with(p))
+            || self
+                .regex_prefix
+                .as_deref()
+                .is_some_and(|p| chunk.starts_with(p))
+    }
+    /// Parses a subscription pattern, returns an error if a formula or a regex is invalid or the
+    /// pattern has got too many levels
+    fn tokenize<'a>(&'a self, pattern: &'a str) -> Result<Vec<PatternToken<'a>>, Error> {
+        let mut tokens = Vec::new();
+        let mut sp = self.split(pattern);
+        while let Some(chunk) = sp.next() {
+            if tokens.len() == self.max_depth {
+                return Err(Error::TopicTooDeep(self.max_depth));
+            }
+            if self.wildcard.contains(chunk) {
+                if self.leading_wildcard && sp.clone().next().is_some() {
+                    tokens.push(PatternToken::Star);
+                    continue;
+                }
+                if self.terminal_wildcard && sp.next().is_some() {
+                    return Err(Error::WildcardPosition(pattern.to_owned()));
+                }
+                tokens.push(PatternToken::Wildcard);
+                break;
+            }
+            if self.wildcard_zero_plus.contains(chunk) {
+                if self.terminal_wildcard && sp.next().is_some() {
+                    return Err(Error::WildcardPosition(pattern.to_owned()));
+                }
+                tokens.push(PatternToken::WildcardZero);
+                break;
+            }
+            if let Some(&levels) = self.match_levels.get(chunk) {
+                // a multi-level token overlaps with other patterns as the same number of
+                // match-any tokens
+                for _ in 0..levels {
+                    if tokens.len() == self.max_depth {
+                        return Err(Error::TopicTooDeep(self.max_depth));
+                    }
+                    tokens.push(PatternToken::Any);
+                }
+                continue;
+            }
+            tokens.push(if self.match_any.contains(chunk) {
+                PatternToken::Any
+            } else if let Some(formula) = self
+                .formula_prefix
+                .as_deref()
+                .and_then(|p| chunk.strip_prefix(p))
+            {
+                PatternToken::Formula(formula.parse()?)
+            } else if let Some(regex) = self
+                .regex_prefix
+                .as_deref()
+                .and_then(|p| chunk.strip_prefix(p))
+            {
+                PatternToken::Regex(
+                    regex::Regex::new(regex).map_err(|e| Error::RegexParseError(e.to_string()))?,
+                )
+            } else {
+                PatternToken::Literal(self.chunk(chunk))
+            });
+        }
+        Ok(tokens)
+    }
+    /// Checks formulas and regular expressions of a subscription topic
+    fn validate(&self, topic: &str) -> Result<(), Error> {
+        let topic = self.parse_shared(topic).map_or(topic, |(_, filter)| filter);
+        self.tokenize(topic).map(|_| ())
+    }
+    #[inline]
+    fn split_once<'a>(&self, topic: &'a str) -> Option<(&'a str, &'a str)> {
+        if let Some(escape) = self.escape_char {
+            let (pos, len) = self.separator.find_unescaped(topic, escape)?;
+            return Some((&topic[..pos], &topic[pos + len..]));
+        }
+        match self.separator {
+            Separator::Char(c) => topic.split_once(c),
+            Separator::Str(ref s) => topic.split_once(s.as_str()),
+            Separator::Chars(ref chars) => topic.split_once(chars.as_slice()),
+        }
+    }
+    /// Splits a shared subscription topic into the group name and the topic filter
+    fn parse_shared<'a>(&self, topic: &'a str) -> Option<(&'a str, &'a str)> {
+        let prefix = self.share_prefix.as_deref()?;
+        let (first, rest) = self.split_once(topic)?;
+        if first != prefix {
+            return None;
+        }
+        let (group, filter) = self.split_once(rest)?;
+        if group.is_empty() {
+            return None;
+        }
+        Some((group, filter))
+    }
+    #[inline]
+    fn is_reserved(&self, chunk: Option<&str>) -> bool {
+        self.reserved_prefix
+            .as_deref()
+            .is_some_and(|p| chunk.is_some_and(|c| c.starts_with(p)))
+    }
+    /// Checks that tokens and prefixes can be told apart while parsing topics
+    fn check_conflicts(&self) -> Result<(), Error> {
+        let conflict = |msg: String| Err(Error::ConfigConflict(msg));
+        if let Some(token) = self.wildcard.iter().find(|t| self.match_any.contains(*t)) {
+            return conflict(format!(
+                "\"{}\" is both wildcard and match-any token",
+                token
+            ));
+        }
+        if let Some(token) = self
+            .wildcard_zero_plus
+            .iter()
+            .find(|t| self.wildcard.contains(*t) || self.match_any.contains(*t))
+        {
+            return conflict(format!(
+                "\"{}\" is both zero-or-more wildcard and another token",
+                token
+            ));
+        }
+        if let Some(token) = self.match_levels.keys().find(|t| {
+            self.wildcard.contains(*t)
+                || self.wildcard_zero_plus.contains(*t)
+                || self.match_any.contains(*t)
+        }) {
+            return conflict(format!(
+                "\"{}\" is both match-levels and another token",
+                token
+            ));
+        }
+        let prefixes = [
+            ("formula prefix", self.formula_prefix.as_deref()),
+            ("regex prefix", self.regex_prefix.as_deref()),
+        ];
+        for token in self
+            .wildcard
+            .iter()
+            .chain(&self.wildcard_zero_plus)
+            .chain(&self.match_any)
+            .chain(self.match_levels.keys())
+        {
+            if token.is_empty() || self.separator.is_in(token) {
+                return conflict(format!("invalid token: \"{}\"", token));
+            }
+            for (kind, prefix) in prefixes {
+                if prefix.is_some_and(|p| token.starts_with(p)) {
+                    return conflict(format!("token \"{}\" starts with the {}", token, kind));
+                }
+            }
+        }
+        for (kind, prefix) in prefixes {
+            if prefix.is_some_and(|p| p.is_empty() || self.separator.is_in(p)) {
+                return conflict(format!("invalid {}", kind));
+            }
+        }
+        if let (Some(f), Some(r)) = (&self.formula_prefix, &self.regex_prefix) {
+            if f.starts_with(r.as_str()) || r.starts_with(f.as_str()) {
+                return conflict("formula and regex prefixes overlap".to_owned());
+            }
+        }
+        Ok(())
+    }
+    /// Returns the name of the first topic matching option which differs from the other settings
+    fn conflict(&self, other: &Settings) -> Option<&'static str> {
+        if self.separator != other.separator {
+            Some("separator")
+        } else if self.escape_char != other.escape_char {
+            Some("escape_char")
+        } else if self.formula_prefix != other.formula_prefix {
+            Some("formula_prefix")
+        } else if self.regex_prefix != other.regex_prefix {
+            Some("regex_prefix")
+        } else if self.match_any != other.match_any {
+            Some("match_any")
+        } else if self.match_levels != other.match_levels {
+            Some("match_levels")
+        } else if self.wildcard != other.wildcard {
+            Some("wildcard")
+        } else if self.wildcard_zero_plus != other.wildcard_zero_plus {
+            Some("wildcard_zero_plus")
+        } else if self.match_any_empty != other.match_any_empty {
+            Some("match_any_empty")
+        } else if self.leading_wildcard != other.leading_wildcard {
+            Some("leading_wildcard")
+        } else if self.wildcard_matches_parent != other.wildcard_matches_parent {
+            Some("wildcard_matches_parent")
+        } else if self.strict_levels != other.strict_levels {
+            Some("strict_levels")
+        } else if self.terminal_wildcard != other.terminal_wildcard {
+            Some("terminal_wildcard")
+        } else if self.case_insensitive != other.case_insensitive {
+            Some("case_insensitive")
+        } else if self.reserved_prefix != other.reserved_prefix {
+            Some("reserved_prefix")
+        } else if self.share_prefix != other.share_prefix {
+            Some("share_prefix")
+        } else {
+            None
+        }
+    }
+}
+
+/// Topics of a client, keyed by normalized topics, values keep the original spelling
+type ClientTopics = Map<String, String>;
+
+#[derive(Debug, Clone)]
+pub struct SubMap<C> {
+    subscriptions: Subscription<C>,
+    shared: Map<String, SharedGroup<C>>,
+    subscribed_topics: Map<C, ClientTopics>,
+    subscription_count: usize,
+    max_topics_per_client: usize,
+    settings: Settings,
+}
+
+/// Token configuration of a [`SubMap`], returned by [`SubMap::tokens`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenReport {
+    pub separator: String,
+    pub wildcard: Set<String>,
+    pub wildcard_zero_plus: Set<String>,
+    pub match_any: Set<String>,
+    pub match_levels: Map<String, usize>,
+    pub formula_prefix: Option<String>,
+    pub regex_prefix: Option<String>,
+    pub reserved_prefix: Option<String>,
+    pub share_prefix: Option<String>,
+}
+
+/// Full configuration of a [`SubMap`] and the engine used, returned by [`SubMap::config`]
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubMapConfig {
+    pub tokens: TokenReport,
+    pub escape_char: Option<char>,
+    pub match_any_empty: bool,
+    pub leading_wildcard: bool,
+    pub wildcard_matches_parent: bool,
+    pub strict_levels: bool,
+    pub terminal_wildcard: bool,
+    pub case_insensitive: bool,
+    pub max_depth: usize,
+    pub max_topics_per_client: usize,
+    pub engine: &'static str,
+}
+
+/// Subscription differences between two maps, returned by [`SubMap::diff`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubMapDiff<C> {
+    pub added: Vec<(C, String)>,
+    pub removed: Vec<(C, String)>,
+}
+
+impl<C> Default for SubMapDiff<C> {
+    fn default() -> Self {
+        Self {
+            added: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+}
+
+impl<C> SubMapDiff<C> {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Subscription changes, made by [`SubMap::set_subscriptions`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SubChange {
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// A handle to a single registered client of a [`SubMap`], returned by [`SubMap::client_entry`].
+/// The entry borrows the map mutably for its lifetime
+pub struct ClientEntry<'a, C> {
+    client: C,
+    topics: &'a mut ClientTopics,
+    subscriptions: &'a mut Subscription<C>,
+    shared: &'a mut Map<String, SharedGroup<C>>,
+    subscription_count: &'a mut usize,
+    max_topics_per_client: usize,
+    settings: &'a Settings,
+}
+
+impl<C> Default for SubMap<C> {
+    fn default() -> Self {
+        Self {
+            subscriptions: <_>::default(),
+            shared: <_>::default(),
+            subscribed_topics: <_>::default(),
+            subscription_count: 0,
+            max_topics_per_client: 0,
+            settings: <_>::default(),
         }
     }
 }
@@ -80,135 +811,531 @@ where
     pub fn new() -> Self {
         Self::default()
     }
+    /// Creates a map with the client index preallocated for the given number of clients. With
+    /// the default B-tree engine the capacity is ignored and the call is equal to
+    /// [`SubMap::new`]
+    #[inline]
+    pub fn with_capacity(clients: usize) -> Self {
+        Self {
+            subscribed_topics: map_with_capacity(clients),
+            ..Self::default()
+        }
+    }
+    /// Finishes the builder chain, returns an error if wildcard/match-any tokens, the separator
+    /// and formula/regex prefixes collide (e.g. the same token is set as a wildcard and as a
+    /// match-any one), as such configurations make topic matching ambiguous
+    pub fn build(self) -> Result<Self, Error> {
+        self.settings.check_conflicts()?;
+        Ok(self)
+    }
+    /// Creates a map with MQTT topic matching rules: "/" is used as the separator, "+" and "#"
+    /// as wildcards, "#" must be the last level and also matches the parent one, topics starting
+    /// with "$" are not matched by top-level wildcards, "$share/<group>/<filter>" topics are
+    /// shared subscriptions
+    pub fn mqtt() -> Self {
+        Self::new()
+            .separator('/')
+            .match_any("+")
+            .wildcard("#")
+            .reserved_prefix("$")
+            .share_prefix("$share")
+            .wildcard_matches_parent(true)
+            .terminal_wildcard(true)
+    }
     #[inline]
     pub fn separator(mut self, separator: char) -> Self {
-        self.separator = separator;
+        self.settings.separator = Separator::Char(separator);
+        self
+    }
+    /// Sets a multi-character separator (e.g. "::")
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the separator is empty
+    #[inline]
+    pub fn separator_str(mut self, separator: &str) -> Self {
+        self.settings.separator = Separator::new(separator);
+        self
+    }
+    /// Sets multiple single-character separators, topics are split on any of them, so e.g.
+    /// "a/b" and "a.b" are the same topic for the map. Subscribed topics are listed as provided,
+    /// subscriptions which differ in separators only are the same subscription
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the separator list is empty
+    #[inline]
+    pub fn separators(mut self, separators: &[char]) -> Self {
+        self.settings.separator = Separator::any_of(separators);
+        self
+    }
+    /// Separators, preceded by the escape char, are treated as parts of topic chunks (e.g. with
+    /// `\` as the escape char, `a/b\/c/d` has got three chunks: `a`, `b\/c` and `d`). Chunks
+    /// are matched as-is, escape chars are not removed. Not set by default
+    #[inline]
+    pub fn escape_char(mut self, escape: char) -> Self {
+        self.settings.escape_char = Some(escape);
         self
     }
     #[inline]
     pub fn formula_prefix(mut self, prefix: &str) -> Self {
-        self.formula_prefix = Some(prefix.to_owned());
+        self.settings.formula_prefix = Some(prefix.to_owned());
         self
     }
     #[inline]
     pub fn regex_prefix(mut self, prefix: &str) -> Self {
-        self.regex_prefix = Some(prefix.to_owned());
+        self.settings.regex_prefix = Some(prefix.to_owned());
         self
     }
     #[inline]
     pub fn wildcard(mut self, wildcard: &str) -> Self {
-        self.wildcard = vec![wildcard.to_owned()].into_iter().collect();
+        self.settings.wildcard = vec![wildcard.to_owned()].into_iter().collect();
+        self
+    }
+    /// Sets the terminal wildcard token, which matches zero or more topic levels, e.g. with "**"
+    /// the pattern "unit/**" matches "unit", "unit/tests" and "unit/tests/t1". Not set by default
+    #[inline]
+    pub fn wildcard_zero_plus(mut self, wildcard: &str) -> Self {
+        self.settings.wildcard_zero_plus = vec![wildcard.to_owned()].into_iter().collect();
         self
     }
     #[inline]
     pub fn match_any(mut self, match_any: &str) -> Self {
-        self.match_any = vec![match_any.to_owned()].into_iter().collect();
+        self.settings.match_any = vec![match_any.to_owned()].into_iter().collect();
+        self
+    }
+    /// Adds a token, which matches exactly the given number of topic levels, e.g. with "++" set
+    /// for 2 levels the pattern "a/++/b" matches "a/x/y/b" but not "a/x/b". Can be called
+    /// multiple times to add tokens for different numbers of levels
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the number of levels is zero
+    #[inline]
+    pub fn match_levels(mut self, token: &str, levels: usize) -> Self {
+        assert!(levels > 0, "the number of levels must be positive");
+        self.settings.match_levels.insert(token.to_owned(), levels);
         self
     }
     #[inline]
     pub fn wildcard_multiple(mut self, wildcard_multiple: &[&str]) -> Self {
-        self.wildcard = wildcard_multiple.iter().map(|&v| v.to_owned()).collect();
+        self.settings.wildcard = wildcard_multiple.iter().map(|&v| v.to_owned()).collect();
         self
     }
     #[inline]
     pub fn match_any_multiple(mut self, match_any_multiple: &[&str]) -> Self {
-        self.match_any = match_any_multiple.iter().map(|&v| v.to_owned()).collect();
+        self.settings.match_any = match_any_multiple.iter().map(|&v| v.to_owned()).collect();
+        self
+    }
+    /// Sets if match-any tokens match empty topic chunks (e.g. "a/?/b" matches "a//b"). The
+    /// default is true
+    #[inline]
+    pub fn match_any_empty(mut self, match_any_empty: bool) -> Self {
+        self.settings.match_any_empty = match_any_empty;
+        self
+    }
+    /// If enabled, wildcard subscriptions also match their parent level (e.g. "unit/#" matches
+    /// "unit"), as MQTT requires. Disabled by default, [`SubMap::mqtt`] enables it.
+    #[inline]
+    pub fn wildcard_matches_parent(mut self, matches_parent: bool) -> Self {
+        self.settings.wildcard_matches_parent = matches_parent;
         self
     }
+    /// Top-level wildcards and match-any tokens do not match topics starting with the reserved
+    /// prefix (e.g. "$" for MQTT system topics), such topics can be subscribed only explicitly
+    /// (e.g. "$SYS/#"). Not set by default, [`SubMap::mqtt`] uses "$".
+    #[inline]
+    pub fn reserved_prefix(mut self, prefix: &str) -> Self {
+        self.settings.reserved_prefix = Some(prefix.to_owned());
+        self
+    }
+    /// Disables the reserved prefix (e.g. set by [`SubMap::mqtt`]), top-level wildcards and
+    /// match-any tokens match all topics
+    #[inline]
+    pub fn no_reserved_prefix(mut self) -> Self {
+        self.settings.reserved_prefix = None;
+        self
+    }
+    /// If enabled, literal topic chunks are matched case-insensitively (e.g. "Sensors/Temp"
+    /// matches "sensors/temp"). Formula and regex chunks are not affected. Topics, returned by
+    /// [`SubMap::list_topics`], are kept as provided, subscriptions which differ in case only are
+    /// the same subscription. Disabled by default.
+    #[inline]
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.settings.case_insensitive = case_insensitive;
+        self
+    }
+    /// If enabled, match-any tokens do not match an empty trailing topic chunk (e.g. "a/?" does
+    /// not match "a/"), empty chunks in other positions are still matched if
+    /// [`SubMap::match_any_empty`] is set. Disabled by default.
+    #[inline]
+    pub fn strict_levels(mut self, strict: bool) -> Self {
+        self.settings.strict_levels = strict;
+        self
+    }
+    /// If enabled, patterns with chunks after a wildcard (e.g. "a/#/b") are refused as invalid
+    /// instead of ignoring the chunks. Has no effect if [`SubMap::leading_wildcard`] is set.
+    /// Disabled by default, [`SubMap::mqtt`] enables it.
+    #[inline]
+    pub fn terminal_wildcard(mut self, terminal: bool) -> Self {
+        self.settings.terminal_wildcard = terminal;
+        self
+    }
+    /// Limits the number of subscription topic levels (1024 by default), deeper topics are
+    /// refused. Queries walk existing tree levels only, so the recursion depth of all operations
+    /// is bounded by the limit
+    #[inline]
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.settings.max_depth = depth;
+        self
+    }
+    /// Topics, starting with the share prefix chunk (e.g. "$share/group1/sensors/+"), are shared
+    /// subscriptions: the chunk after the prefix is the group name and the rest is the topic
+    /// filter. As in MQTT 5, a group is identified by both the name and the filter, so e.g.
+    /// "$share/g/a/+" and "$share/g/a/#" are different groups. [`SubMap::get_subscribers_shared`]
+    /// returns a single member of each matching group, other methods return all group members.
+    /// Not set by default, [`SubMap::mqtt`] uses "$share".
+    #[inline]
+    pub fn share_prefix(mut self, prefix: &str) -> Self {
+        self.settings.share_prefix = Some(prefix.to_owned());
+        self
+    }
+    /// Allows wildcards in non-terminal positions, which match zero or more chunks (e.g. "*/x"
+    /// matches all topics ending with "x", "a/*/x" matches "a/x", "a/b/x", "a/b/c/x" etc.). If
+    /// disabled (the default), all chunks after a wildcard are ignored.
+    ///
+    /// Note: such subscriptions are slow, as all possible topic suffixes must be tried against
+    /// them on every lookup.
+    #[inline]
+    pub fn leading_wildcard(mut self, leading_wildcard: bool) -> Self {
+        self.settings.leading_wildcard = leading_wildcard;
+        self
+    }
+    /// Returns the separator, wildcard/match-any tokens and prefixes the map is configured with
+    pub fn tokens(&self) -> TokenReport {
+        TokenReport {
+            separator: self.settings.separator.to_string(),
+            wildcard: self.settings.wildcard.clone(),
+            wildcard_zero_plus: self.settings.wildcard_zero_plus.clone(),
+            match_any: self.settings.match_any.clone(),
+            match_levels: self.settings.match_levels.clone(),
+            formula_prefix: self.settings.formula_prefix.clone(),
+            regex_prefix: self.settings.regex_prefix.clone(),
+            reserved_prefix: self.settings.reserved_prefix.clone(),
+            share_prefix: self.settings.share_prefix.clone(),
+        }
+    }
+    /// Returns the map configuration, e.g. to report it for diagnostics
+    pub fn config(&self) -> SubMapConfig {
+        SubMapConfig {
+            tokens: self.tokens(),
+            escape_char: self.settings.escape_char,
+            match_any_empty: self.settings.match_any_empty,
+            leading_wildcard: self.settings.leading_wildcard,
+            wildcard_matches_parent: self.settings.wildcard_matches_parent,
+            strict_levels: self.settings.strict_levels,
+            terminal_wildcard: self.settings.terminal_wildcard,
+            case_insensitive: self.settings.case_insensitive,
+            max_depth: self.settings.max_depth,
+            max_topics_per_client: self.max_topics_per_client,
+            engine: ENGINE,
+        }
+    }
     #[inline]
     pub fn list_clients(&self) -> Vec<C> {
         self.subscribed_topics.keys().cloned().collect()
     }
+    /// Returns registered clients which have got no subscriptions
+    pub fn idle_clients(&self) -> Vec<&C> {
+        self.subscribed_topics
+            .iter()
+            .filter_map(|(client, topics)| topics.is_empty().then_some(client))
+            .collect()
+    }
     #[inline]
     pub fn list_topics(&self, client: &C) -> Vec<&str> {
         if let Some(topics) = self.subscribed_topics.get(client) {
-            topics.iter().map(String::as_str).collect()
+            topics.values().map(String::as_str).collect()
         } else {
             Vec::new()
         }
     }
+    /// Same as [`SubMap::list_topics`] but returns owned strings, which do not borrow the map
     #[inline]
-    pub fn is_empty(&self) -> bool {
-        self.subscribed_topics.is_empty()
+    pub fn list_topics_owned(&self, client: &C) -> Vec<String> {
+        if let Some(topics) = self.subscribed_topics.get(client) {
+            topics.values().cloned().collect()
+        } else {
+            Vec::new()
+        }
+    }
+    /// Iterates over all (client, topic) subscription pairs
+    #[inline]
+    pub fn iter_subscriptions(&self) -> impl Iterator<Item = (&C, &str)> {
+        self.subscribed_topics
+            .iter()
+            .flat_map(|(client, topics)| topics.values().map(move |topic| (client, topic.as_str())))
+    }
+    /// Returns the number of topics (patterns) the client is subscribed to
+    #[inline]
+    pub fn client_topic_count(&self, client: &C) -> usize {
+        self.subscribed_topics.get(client).map_or(0, Map::len)
+    }
+    /// Checks if the client has got the exact topic (pattern) subscription, wildcards are not
+    /// expanded
+    #[inline]
+    pub fn has_subscription(&self, client: &C, topic: &str) -> bool {
+        self.subscribed_topics
+            .get(client)
+            .is_some_and(|topics| topics.contains_key(&*self.settings.normalize(topic)))
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.subscribed_topics.is_empty()
+    }
+    /// Shrinks the capacity of internal maps and sets of the subscription tree and the client
+    /// index as much as possible, e.g. after a large number of clients has been removed. A no-op
+    /// with the default B-tree engine
+    pub fn shrink_to_fit(&mut self) {
+        self.subscriptions.shrink_to_fit();
+        for group in self.shared.values_mut() {
+            group.subscriptions.shrink_to_fit();
+        }
+        self.shared.shrink_to_fit();
+        for topics in self.subscribed_topics.values_mut() {
+            topics.shrink_to_fit();
+        }
+        self.subscribed_topics.shrink_to_fit();
+    }
+    /// Removes all clients and subscriptions, the map configuration is kept
+    pub fn clear(&mut self) {
+        self.subscriptions = Subscription::default();
+        self.shared.clear();
+        self.subscribed_topics.clear();
+        self.subscription_count = 0;
+    }
+    /// Removes all clients and subscriptions, like [`SubMap::clear`], and returns the removed
+    /// (client, topic) subscription pairs
+    pub fn drain(&mut self) -> Vec<(C, String)> {
+        let subscribed_topics = std::mem::take(&mut self.subscribed_topics);
+        let mut result = Vec::with_capacity(self.subscription_count);
+        self.clear();
+        for (client, topics) in subscribed_topics {
+            for topic in topics.into_values() {
+                result.push((client.clone(), topic));
+            }
+        }
+        result
     }
     pub fn register_client(&mut self, client: &C) -> bool {
         if self.subscribed_topics.contains_key(client) {
             false
         } else {
-            self.subscribed_topics.insert(client.clone(), Set::new());
+            self.subscribed_topics
+                .insert(client.clone(), Map::default());
             true
         }
     }
+    #[inline]
     pub fn unregister_client(&mut self, client: &C) -> bool {
-        if let Some(client_topics) = self.subscribed_topics.remove(client) {
-            for topic in client_topics {
-                unsubscribe_rec(
-                    &mut self.subscriptions,
-                    topic.split(self.separator),
-                    client,
-                    &self.wildcard,
-                    &self.match_any,
-                    self.formula_prefix.as_deref(),
-                    self.regex_prefix.as_deref(),
-                );
-                self.subscription_count -= 1;
+        self.take_client_topics(client).is_some()
+    }
+    /// Unregisters the client and returns topics it has been subscribed to, None if the client is
+    /// not registered
+    pub fn take_client_topics(&mut self, client: &C) -> Option<Vec<String>> {
+        let client_topics = self.subscribed_topics.remove_key(client)?;
+        for topic in client_topics.keys() {
+            unsubscribe_topic(
+                &mut self.subscriptions,
+                &mut self.shared,
+                topic,
+                client,
+                &self.settings,
+            );
+        }
+        self.subscription_count -= client_topics.len();
+        Some(client_topics.into_values().collect())
+    }
+    /// Renames the client, keeping all its subscriptions. Returns false if the old client is not
+    /// registered or the new one is already registered
+    pub fn rename_client(&mut self, old: &C, new: &C) -> bool {
+        if self.subscribed_topics.contains_key(new) {
+            return false;
+        }
+        let Some(client_topics) = self.subscribed_topics.remove_key(old) else {
+            return false;
+        };
+        for topic in client_topics.keys() {
+            if let Some(clients) = topic_client_set_mut(
+                &mut self.subscriptions,
+                &mut self.shared,
+                topic,
+                &self.settings,
+            ) {
+                clients.remove_key(old);
+                clients.insert(new.clone());
             }
-            true
-        } else {
-            false
         }
+        self.subscribed_topics.insert(new.clone(), client_topics);
+        true
     }
+    /// Unregisters multiple clients, returns the number of clients which were registered
+    pub fn unregister_clients(&mut self, clients: &[C]) -> usize {
+        clients
+            .iter()
+            .filter(|client| self.unregister_client(client))
+            .count()
+    }
+    #[inline]
     pub fn subscribe(&mut self, topic: &str, client: &C) -> bool {
-        self.subscribed_topics
-            .get_mut(client)
-            .map_or(false, |client_topics| {
-                if !client_topics.contains(topic) {
-                    subscribe_rec(
-                        &mut self.subscriptions,
-                        topic.split(self.separator),
-                        client,
-                        &self.wildcard,
-                        &self.match_any,
-                        self.formula_prefix.as_deref(),
-                        self.regex_prefix.as_deref(),
-                    );
-                    client_topics.insert(topic.to_owned());
-                    self.subscription_count += 1;
-                }
-                true
-            })
+        self.subscribe_new(topic, client).is_some()
+    }
+    /// Same as [`SubMap::subscribe`] but the topic is given as bytes. Tree keys are strings, so
+    /// the topic must be valid UTF-8 (ASCII topics are validated in a single fast pass), false is
+    /// returned otherwise
+    #[inline]
+    pub fn subscribe_bytes(&mut self, topic: &[u8], client: &C) -> bool {
+        std::str::from_utf8(topic).is_ok_and(|topic| self.subscribe(topic, client))
+    }
+    /// Subscribes the client to the topic. Returns None if the client is not registered, has
+    /// reached the topic limit or the topic contains an invalid formula/regex, Some(true) if the
+    /// subscription has been added and Some(false) if the client has been already subscribed to
+    /// the topic
+    #[inline]
+    pub fn subscribe_new(&mut self, topic: &str, client: &C) -> Option<bool> {
+        self.subscribe_checked(topic, client).ok().flatten()
+    }
+    /// Same as [`SubMap::subscribe`] but returns an error if the topic contains an invalid
+    /// formula/regex
+    #[inline]
+    pub fn try_subscribe(&mut self, topic: &str, client: &C) -> Result<bool, Error> {
+        self.subscribe_checked(topic, client).map(|r| r.is_some())
+    }
+    fn subscribe_checked(&mut self, topic: &str, client: &C) -> Result<Option<bool>, Error> {
+        let Some(client_topics) = self.subscribed_topics.get_mut(client) else {
+            return Ok(None);
+        };
+        let normalized = self.settings.normalize(topic);
+        if client_topics.contains_key(&*normalized) {
+            return Ok(Some(false));
+        }
+        if self.max_topics_per_client > 0 && client_topics.len() >= self.max_topics_per_client {
+            return Ok(None);
+        }
+        subscribe_topic(
+            &mut self.subscriptions,
+            &mut self.shared,
+            &normalized,
+            client,
+            &self.settings,
+        )?;
+        client_topics.insert(normalized.into_owned(), topic.to_owned());
+        self.subscription_count += 1;
+        Ok(Some(true))
+    }
+    /// Returns an entry for a registered client, which allows to perform several operations on
+    /// the client without looking it up every time. Returns None if the client is not registered
+    pub fn client_entry(&mut self, client: &C) -> Option<ClientEntry<'_, C>> {
+        let topics = self.subscribed_topics.get_mut(client)?;
+        Some(ClientEntry {
+            client: client.clone(),
+            topics,
+            subscriptions: &mut self.subscriptions,
+            shared: &mut self.shared,
+            subscription_count: &mut self.subscription_count,
+            max_topics_per_client: self.max_topics_per_client,
+            settings: &self.settings,
+        })
+    }
+    /// Subscribes the client to multiple topics, returns the number of new subscriptions (zero if
+    /// the client is not registered). Topics over the client topic limit and ones with invalid
+    /// formulas/regexes are skipped
+    pub fn subscribe_many(&mut self, topics: &[&str], client: &C) -> usize {
+        let Some(client_topics) = self.subscribed_topics.get_mut(client) else {
+            return 0;
+        };
+        let mut added = 0;
+        for topic in topics {
+            if self.max_topics_per_client > 0 && client_topics.len() >= self.max_topics_per_client {
+                break;
+            }
+            let normalized = self.settings.normalize(topic);
+            if !client_topics.contains_key(&*normalized)
+                && subscribe_topic(
+                    &mut self.subscriptions,
+                    &mut self.shared,
+                    &normalized,
+                    client,
+                    &self.settings,
+                )
+                .is_ok()
+            {
+                client_topics.insert(normalized.into_owned(), (*topic).to_owned());
+                added += 1;
+            }
+        }
+        self.subscription_count += added;
+        added
     }
     pub fn unsubscribe(&mut self, topic: &str, client: &C) -> bool {
         self.subscribed_topics
             .get_mut(client)
-            .map_or(false, |client_topics| {
-                if client_topics.contains(topic) {
-                    unsubscribe_rec(
+            .is_some_and(|client_topics| {
+                let normalized = self.settings.normalize(topic);
+                if client_topics.remove_key(&*normalized).is_some() {
+                    unsubscribe_topic(
                         &mut self.subscriptions,
-                        topic.split('/'),
+                        &mut self.shared,
+                        &normalized,
                         client,
-                        &self.wildcard,
-                        &self.match_any,
-                        self.formula_prefix.as_deref(),
-                        self.regex_prefix.as_deref(),
+                        &self.settings,
                     );
-                    client_topics.remove(topic);
                     self.subscription_count -= 1;
                 }
                 true
             })
     }
+    /// Unsubscribes the client from all topics (patterns), which start with the given chunks,
+    /// e.g. the prefix "sensors" matches "sensors", "sensors/temp" and "sensors/#" but not
+    /// "sensors2/temp". Chunks are compared literally, wildcards are not expanded. Returns the
+    /// number of removed subscriptions
+    pub fn unsubscribe_matching(&mut self, prefix: &str, client: &C) -> usize {
+        let Some(client_topics) = self.subscribed_topics.get_mut(client) else {
+            return 0;
+        };
+        let matching: Vec<String> = client_topics
+            .keys()
+            .filter(|topic| {
+                let mut sp = self.settings.split(topic);
+                self.settings.split(prefix).all(|chunk| {
+                    sp.next()
+                        .is_some_and(|c| self.settings.chunk(c) == self.settings.chunk(chunk))
+                })
+            })
+            .cloned()
+            .collect();
+        for topic in &matching {
+            unsubscribe_topic(
+                &mut self.subscriptions,
+                &mut self.shared,
+                topic,
+                client,
+                &self.settings,
+            );
+            client_topics.remove_key(topic);
+        }
+        self.subscription_count -= matching.len();
+        matching.len()
+    }
     pub fn unsubscribe_all(&mut self, client: &C) -> bool {
         if let Some(client_topics) = self.subscribed_topics.get_mut(client) {
-            for topic in &*client_topics {
-                unsubscribe_rec(
+            for topic in client_topics.keys() {
+                unsubscribe_topic(
                     &mut self.subscriptions,
-                    topic.split(self.separator),
+                    &mut self.shared,
+                    topic,
                     client,
-                    &self.wildcard,
-                    &self.match_any,
-                    self.formula_prefix.as_deref(),
-                    self.regex_prefix.as_deref(),
+                    &self.settings,
                 );
                 self.subscription_count -= 1;
             }
@@ -218,26 +1345,445 @@ where
             false
         }
     }
+    /// Makes the client subscriptions exactly match the given topic list: subscribes the client
+    /// to missing topics and unsubscribes from ones not in the list. If the client is not
+    /// registered, nothing is changed.
+    pub fn set_subscriptions(&mut self, client: &C, topics: &[&str]) -> SubChange {
+        let mut change = SubChange::default();
+        let Some(client_topics) = self.subscribed_topics.get(client) else {
+            return change;
+        };
+        let desired: Map<String, &str> = topics
+            .iter()
+            .map(|topic| (self.settings.normalize(topic).into_owned(), *topic))
+            .collect();
+        let stale: Vec<String> = client_topics
+            .keys()
+            .filter(|topic| !desired.contains_key(topic.as_str()))
+            .cloned()
+            .collect();
+        for topic in stale {
+            self.unsubscribe(&topic, client);
+            change.removed += 1;
+        }
+        for topic in desired.into_values() {
+            if self.subscribe_new(topic, client) == Some(true) {
+                change.added += 1;
+            }
+        }
+        change
+    }
+    /// Compares subscriptions with a newer snapshot of the map: subscriptions present in the other
+    /// map only are returned as added and ones missing there as removed. Replaying the diff
+    /// makes this map subscriptions equal to the other one
+    pub fn diff(&self, other: &SubMap<C>) -> SubMapDiff<C> {
+        let mut diff = SubMapDiff::default();
+        let empty = Map::default();
+        for (client, topics) in &self.subscribed_topics {
+            let other_topics = other.subscribed_topics.get(client).unwrap_or(&empty);
+            for (normalized, topic) in topics {
+                if !other_topics.contains_key(normalized) {
+                    diff.removed.push((client.clone(), topic.clone()));
+                }
+            }
+        }
+        for (client, topics) in &other.subscribed_topics {
+            let own_topics = self.subscribed_topics.get(client).unwrap_or(&empty);
+            for (normalized, topic) in topics {
+                if !own_topics.contains_key(normalized) {
+                    diff.added.push((client.clone(), topic.clone()));
+                }
+            }
+        }
+        diff
+    }
+    /// Exports all subscriptions as a flat list of (client, topic) pairs, which can be stored and
+    /// loaded back with [`SubMap::import`]. Registered clients without subscriptions are not
+    /// exported
+    pub fn export(&self) -> Vec<(C, String)> {
+        self.iter_subscriptions()
+            .map(|(client, topic)| (client.clone(), topic.to_owned()))
+            .collect()
+    }
+    /// Registers clients and replays subscriptions, exported with [`SubMap::export`]
+    pub fn import(&mut self, entries: &[(C, String)]) {
+        for (client, topic) in entries {
+            self.register_client(client);
+            self.subscribe(topic, client);
+        }
+    }
+    /// Absorbs all clients and subscriptions of another map. Both maps must have the same topic
+    /// matching configuration, otherwise an error is returned and nothing is changed. The client
+    /// topic limit of this map is applied to the merged clients.
+    pub fn merge(&mut self, other: SubMap<C>) -> Result<(), Error> {
+        if let Some(option) = self.settings.conflict(&other.settings) {
+            return Err(Error::ConfigConflict(format!("{} differs", option)));
+        }
+        for (client, topics) in other.subscribed_topics {
+            self.register_client(&client);
+            for topic in topics.into_values() {
+                self.subscribe(&topic, &client);
+            }
+        }
+        Ok(())
+    }
     #[inline]
     pub fn get_subscribers(&self, topic: &str) -> Set<C> {
-        let mut result = Set::new();
+        let mut result = Set::default();
+        self.get_subscribers_into(topic, &mut result);
+        result
+    }
+    /// Same as [`SubMap::get_subscribers`] but the topic is given as bytes. The topic must be
+    /// valid UTF-8, otherwise it matches nothing
+    #[inline]
+    pub fn get_subscribers_bytes(&self, topic: &[u8]) -> Set<C> {
+        std::str::from_utf8(topic)
+            .map_or_else(|_| Set::default(), |topic| self.get_subscribers(topic))
+    }
+    /// Clears the provided set and fills it with the topic subscribers, allows to reuse the same
+    /// set for multiple calls
+    pub fn get_subscribers_into(&self, topic: &str, out: &mut Set<C>) {
+        out.clear();
+        for subscriptions in self.trees() {
+            get_subscribers_rec(
+                subscriptions,
+                self.settings.split(topic),
+                &self.settings,
+                true,
+                out,
+            );
+        }
+    }
+    /// Returns topic subscribers or None if there are no ones. The tree is traversed once and
+    /// empty sets do not allocate, so this is cheaper than checking [`SubMap::is_subscribed`]
+    /// before [`SubMap::get_subscribers`]
+    #[inline]
+    pub fn get_subscribers_if_any(&self, topic: &str) -> Option<Set<C>> {
+        let subscribers = self.get_subscribers(topic);
+        (!subscribers.is_empty()).then_some(subscribers)
+    }
+    /// Returns the union of subscribers of multiple topics, each client is included once
+    pub fn get_subscribers_multi(&self, topics: &[&str]) -> Set<C> {
+        let mut result = Set::default();
+        for topic in topics {
+            for subscriptions in self.trees() {
+                get_subscribers_rec(
+                    subscriptions,
+                    self.settings.split(topic),
+                    &self.settings,
+                    true,
+                    &mut result,
+                );
+            }
+        }
+        result
+    }
+    /// Returns an iterator over topic subscribers, which walks the subscription tree lazily. Each
+    /// client is returned only once, no matter how many subscriptions match the topic
+    pub fn get_subscribers_iter<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a C> + 'a {
+        SubscribersIter {
+            settings: &self.settings,
+            nodes: self
+                .trees()
+                .map(|subscriptions| (subscriptions, self.settings.split(topic), true))
+                .collect(),
+            sets: Vec::new(),
+            current: None,
+            visited: Set::default(),
+        }
+    }
+    /// Returns topic subscribers paired with their patterns which match the topic (a client may
+    /// be returned several times if multiple patterns match). The subscription tree is walked
+    /// once, then each pattern of the found clients is looked up in the tree to check if it ends
+    /// at one of the matched nodes
+    pub fn matching_patterns(&self, topic: &str) -> Vec<(&C, String)> {
+        let mut matched: Set<*const Set<C>> = Set::default();
+        let mut clients: Vec<&C> = Vec::new();
+        let mut visited: Set<&C> = Set::default();
+        for subscriptions in self.trees() {
+            matching_sets_rec(
+                subscriptions,
+                self.settings.split(topic),
+                &self.settings,
+                true,
+                &mut |subscribers| {
+                    if !subscribers.is_empty() && matched.insert(std::ptr::from_ref(subscribers)) {
+                        clients.extend(subscribers.iter().filter(|c| visited.insert(*c)));
+                    }
+                },
+            );
+        }
+        let mut result = Vec::new();
+        for client in clients {
+            let Some((client, patterns)) = self.subscribed_topics.get_key_value(client) else {
+                continue;
+            };
+            for (normalized, pattern) in patterns {
+                let found = topic_client_set(
+                    &self.subscriptions,
+                    &self.shared,
+                    normalized,
+                    &self.settings,
+                )
+                .is_some_and(|subscribers| matched.contains(&std::ptr::from_ref(subscribers)));
+                if found {
+                    result.push((client, pattern.clone()));
+                }
+            }
+        }
+        result
+    }
+    /// Calls the function for each topic subscriber (once per client), the walk is stopped when
+    /// the function returns [`ControlFlow::Break`]. The subscriber set is never materialized
+    pub fn for_each_subscriber<F>(&self, topic: &str, mut f: F)
+    where
+        F: FnMut(&C) -> ControlFlow<()>,
+    {
+        for client in self.get_subscribers_iter(topic) {
+            if f(client).is_break() {
+                break;
+            }
+        }
+    }
+    /// Returns the number of topic subscribers without cloning them.
+    ///
+    /// The value is not cached: the subscription tree is walked (O(matching nodes)) and already
+    /// counted clients are tracked to exclude duplicates.
+    #[inline]
+    pub fn get_subscribers_count(&self, topic: &str) -> usize {
+        self.get_subscribers_iter(topic).count()
+    }
+    /// Clears the provided vector and fills it with the topic subscribers, allows to reuse the
+    /// same vector for multiple calls.
+    ///
+    /// As a client may be subscribed to the topic via several patterns, the vector is sorted and
+    /// deduplicated after filling, which costs O(n log n) for n collected entries (with the
+    /// hashmap engine the vector is deduplicated only, keeping the collection order).
+    pub fn get_subscribers_into_vec(&self, topic: &str, out: &mut Vec<C>) {
+        out.clear();
+        for subscriptions in self.trees() {
+            get_subscribers_rec(
+                subscriptions,
+                self.settings.split(topic),
+                &self.settings,
+                true,
+                out,
+            );
+        }
+        if out.len() > 1 {
+            dedup_clients(out);
+        }
+    }
+    /// Returns clients, subscribed to the wildcard rooted at the given prefix (e.g. "prefix/#"
+    /// for MQTT-style maps). The prefix is navigated literally, an empty prefix means the root.
+    pub fn subtree_wildcard_subscribers(&self, prefix: &str) -> Set<C> {
+        let mut subscription = &self.subscriptions;
+        if !prefix.is_empty() {
+            for chunk in self.settings.split(prefix) {
+                let Some(sub) = subscription.subtopics.get(&*self.settings.chunk(chunk)) else {
+                    return Set::default();
+                };
+                subscription = sub;
+            }
+        }
+        let mut result = subscription.sub_any.clone();
+        result.extend(subscription.sub_any_zero.iter().cloned());
+        result
+    }
+    /// Returns distinct subscribed patterns, which leading levels are equal to the prefix levels,
+    /// sorted. Pattern levels are compared literally, so wildcards and formulas match only
+    /// themselves. Shared subscriptions are compared by their topic filters. An empty prefix
+    /// returns all patterns
+    pub fn topics_under(&self, prefix: &str) -> Vec<String> {
+        let mut prefix_chunks: Vec<Cow<str>> = if prefix.is_empty() {
+            Vec::new()
+        } else {
+            self.settings
+                .split(prefix)
+                .map(|chunk| self.settings.chunk(chunk))
+                .collect()
+        };
+        // allow a trailing separator, e.g. "sensors/"
+        if prefix_chunks.len() > 1 && prefix_chunks.last().is_some_and(|c| c.is_empty()) {
+            prefix_chunks.pop();
+        }
+        let mut result: Vec<String> = self
+            .subscribed_topics
+            .values()
+            .flat_map(Map::values)
+            .filter(|topic| {
+                let filter = self
+                    .settings
+                    .parse_shared(topic)
+                    .map_or(topic.as_str(), |(_, filter)| filter);
+                let mut chunks = self.settings.split(filter);
+                prefix_chunks
+                    .iter()
+                    .all(|p| chunks.next().is_some_and(|c| self.settings.chunk(c) == *p))
+            })
+            .cloned()
+            .collect();
+        result.sort();
+        result.dedup();
+        result
+    }
+    /// Returns topic subscribers, like [`SubMap::get_subscribers`], but only one member of each
+    /// matching shared subscription group is included. Group members are picked in round-robin
+    /// order.
+    pub fn get_subscribers_shared(&self, topic: &str) -> Set<C> {
+        let mut result = Set::default();
         get_subscribers_rec(
             &self.subscriptions,
-            topic.split(self.separator),
-            self.formula_prefix.as_deref(),
-            self.regex_prefix.as_deref(),
+            self.settings.split(topic),
+            &self.settings,
+            true,
             &mut result,
         );
+        let mut members = Vec::new();
+        for group in self.shared.values() {
+            members.clear();
+            get_subscribers_rec(
+                &group.subscriptions,
+                self.settings.split(topic),
+                &self.settings,
+                true,
+                &mut members,
+            );
+            if !members.is_empty() {
+                dedup_clients(&mut members);
+                let n = group.next.fetch_add(1, Ordering::Relaxed);
+                result.insert(members.swap_remove(n % members.len()));
+            }
+        }
         result
     }
     #[inline]
     pub fn is_subscribed(&self, topic: &str) -> bool {
-        is_subscribed_rec(
-            &self.subscriptions,
-            self.formula_prefix.as_deref(),
-            self.regex_prefix.as_deref(),
-            topic.split(self.separator),
-        )
+        self.trees().any(|subscriptions| {
+            is_subscribed_rec(
+                subscriptions,
+                &self.settings,
+                true,
+                self.settings.split(topic),
+                &|subscribers| !subscribers.is_empty(),
+            )
+        })
+    }
+    /// Returns the number of nodes in the subscription trees (including roots of the main tree and
+    /// shared subscription groups), which reflects the memory usage better than the number of
+    /// subscriptions, as subscriptions share nodes
+    pub fn node_count(&self) -> usize {
+        self.trees().map(Subscription::node_count).sum()
+    }
+    /// Returns true if the topic has got subscribers of the exact (literal) pattern, wildcard,
+    /// match-any, formula and regex subscriptions are ignored
+    pub fn has_exact_subscriber(&self, topic: &str) -> bool {
+        self.trees().any(|subscriptions| {
+            let mut subscription = subscriptions;
+            for chunk in self.settings.split(topic) {
+                let Some(sub) = subscription.subtopics.get(&*self.settings.chunk(chunk)) else {
+                    return false;
+                };
+                subscription = sub;
+            }
+            !subscription.subscribers.is_empty()
+        })
+    }
+    /// Returns true if the particular client is subscribed to the topic. The tree is walked until
+    /// the client is found, which is cheaper than collecting all topic subscribers
+    #[inline]
+    pub fn is_subscriber(&self, topic: &str, client: &C) -> bool {
+        self.trees().any(|subscriptions| {
+            is_subscribed_rec(
+                subscriptions,
+                &self.settings,
+                true,
+                self.settings.split(topic),
+                &|subscribers| subscribers.contains(client),
+            )
+        })
+    }
+    /// Checks the subscription pattern without subscribing: formulas must parse, regular
+    /// expressions must compile and the number of levels must not exceed the max depth. Shared
+    /// subscriptions are checked by their topic filters
+    #[inline]
+    pub fn validate_pattern(&self, pattern: &str) -> Result<(), Error> {
+        self.settings.validate(pattern)
+    }
+    /// Clears the cache of compiled regular expressions, used in queried topics (the cache is
+    /// also cleared automatically when gets too large)
+    pub fn clear_regex_cache(&self) {
+        self.settings.regex_cache.clear();
+    }
+    /// Checks if there is a topic both patterns match.
+    ///
+    /// The check is exact for literal chunks, match-any tokens, wildcards and comparison/range
+    /// formulas with the same prefix. Regular expressions are assumed to intersect with each
+    /// other and with formulas, as well as other formula functions (e.g. `mod`) and formulas
+    /// with different prefixes, if one prefix starts with another. Patterns with invalid formulas
+    /// or regular expressions match nothing and never overlap.
+    pub fn patterns_overlap(&self, a: &str, b: &str) -> bool {
+        let (Ok(a), Ok(b)) = (self.settings.tokenize(a), self.settings.tokenize(b)) else {
+            return false;
+        };
+        patterns_overlap_rec(&a, &b, &self.settings, true, &mut Map::default())
+    }
+    /// Returns client topics (patterns), which are made redundant by broader patterns the client
+    /// is also subscribed to, e.g. "a/b/c" if there is "a/#", sorted. Of equivalent patterns all
+    /// but the first one are returned.
+    ///
+    /// The check is conservative: patterns with leading wildcards are not analyzed, formulas and
+    /// regular expressions are considered as covered by match-any tokens, wildcards and
+    /// identical formulas/expressions only. Shared subscriptions are ignored
+    pub fn redundant_topics(&self, client: &C) -> Vec<String> {
+        let Some(topics) = self.subscribed_topics.get(client) else {
+            return Vec::new();
+        };
+        let patterns: Vec<(&String, Vec<PatternToken>)> = topics
+            .values()
+            .filter(|topic| self.settings.parse_shared(topic).is_none())
+            .filter_map(|topic| {
+                self.settings
+                    .tokenize(topic)
+                    .ok()
+                    .map(|tokens| (topic, tokens))
+            })
+            .collect();
+        let mut result: Vec<String> = patterns
+            .iter()
+            .filter(|(topic, tokens)| {
+                patterns.iter().any(|(other, other_tokens)| {
+                    other != topic
+                        && pattern_covers_rec(other_tokens, tokens, &self.settings, true)
+                        && (other < topic
+                            || !pattern_covers_rec(tokens, other_tokens, &self.settings, true))
+                })
+            })
+            .map(|(topic, _)| (*topic).clone())
+            .collect();
+        result.sort();
+        result
+    }
+    /// Unsubscribes the client from topics, returned by [`SubMap::redundant_topics`], returns the
+    /// number of removed subscriptions
+    pub fn compact_client(&mut self, client: &C) -> usize {
+        let redundant = self.redundant_topics(client);
+        for topic in &redundant {
+            self.unsubscribe(topic, client);
+        }
+        redundant.len()
+    }
+    /// The main subscription tree and trees of shared subscription groups
+    #[inline]
+    fn trees(&self) -> impl Iterator<Item = &Subscription<C>> {
+        std::iter::once(&self.subscriptions).chain(self.shared.values().map(|g| &g.subscriptions))
+    }
+    /// Limits the number of topics (patterns) a client can be subscribed to, subscriptions over
+    /// the limit are refused. Zero (the default) means no limit. Existing subscriptions are not
+    /// affected
+    #[inline]
+    pub fn set_max_topics_per_client(&mut self, max: usize) {
+        self.max_topics_per_client = max;
     }
     #[inline]
     pub fn subscription_count(&self) -> usize {
@@ -249,182 +1795,549 @@ where
     }
 }
 
-#[allow(clippy::too_many_lines)]
-fn subscribe_rec<C>(
-    subscription: &mut Subscription<C>,
-    mut sp: Split<char>,
-    client: &C,
-    wildcard: &Set<String>,
-    match_any: &Set<String>,
-    formula_prefix: Option<&str>,
-    regex_prefix: Option<&str>,
-) where
+impl<C> ClientEntry<'_, C>
+where
     C: Client,
 {
-    if let Some(topic) = sp.next() {
-        if wildcard.contains(topic) {
-            subscription.sub_any.insert(client.clone());
-        } else if match_any.contains(topic) {
-            if let Some(ref mut sub) = subscription.subtopics_any {
-                subscribe_rec(
-                    sub,
-                    sp,
-                    client,
-                    wildcard,
-                    match_any,
-                    formula_prefix,
-                    regex_prefix,
-                );
-            } else {
-                let mut sub = Subscription::default();
-                subscribe_rec(
-                    &mut sub,
-                    sp,
-                    client,
-                    wildcard,
-                    match_any,
-                    formula_prefix,
-                    regex_prefix,
-                );
-                subscription.subtopics_any = Some(Box::new(sub));
-            }
-        } else if let Some(formula) = formula_prefix.and_then(|p| topic.strip_prefix(p)) {
-            let Ok(formula_parsed) = formula.parse::<Formula>() else {
-                return;
-            };
-            if let Some(sub) = subscription.subtopics_by_formula.get_mut(&formula_parsed) {
-                subscribe_rec(
-                    sub,
-                    sp,
-                    client,
-                    wildcard,
-                    match_any,
-                    formula_prefix,
-                    regex_prefix,
-                );
+    #[inline]
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+    /// Topics (patterns) the client is subscribed to
+    #[inline]
+    pub fn topics(&self) -> impl Iterator<Item = &str> {
+        self.topics.values().map(String::as_str)
+    }
+    #[inline]
+    pub fn has_subscription(&self, topic: &str) -> bool {
+        self.topics.contains_key(&*self.settings.normalize(topic))
+    }
+    /// Subscribes the client to the topic, same as [`SubMap::subscribe`]
+    pub fn subscribe(&mut self, topic: &str) -> bool {
+        let normalized = self.settings.normalize(topic);
+        if self.topics.contains_key(&*normalized) {
+            return true;
+        }
+        if self.max_topics_per_client > 0 && self.topics.len() >= self.max_topics_per_client {
+            return false;
+        }
+        if subscribe_topic(
+            self.subscriptions,
+            self.shared,
+            &normalized,
+            &self.client,
+            self.settings,
+        )
+        .is_err()
+        {
+            return false;
+        }
+        self.topics
+            .insert(normalized.into_owned(), topic.to_owned());
+        *self.subscription_count += 1;
+        true
+    }
+    /// Unsubscribes the client from the topic, returns false if the client has not been
+    /// subscribed to it
+    pub fn unsubscribe(&mut self, topic: &str) -> bool {
+        let normalized = self.settings.normalize(topic);
+        if self.topics.remove_key(&*normalized).is_none() {
+            return false;
+        }
+        unsubscribe_topic(
+            self.subscriptions,
+            self.shared,
+            &normalized,
+            &self.client,
+            self.settings,
+        );
+        *self.subscription_count -= 1;
+        true
+    }
+}
+
+/// Checks if two patterns overlap. Results are memoized by the numbers of remaining tokens (the
+/// root flag is determined by them as well), so patterns with non-terminal wildcards are
+/// compared in O(n*m) instead of trying all the ways wildcards can take chunks
+fn patterns_overlap_rec(
+    a: &[PatternToken],
+    b: &[PatternToken],
+    settings: &Settings,
+    root: bool,
+    memo: &mut Map<(usize, usize), bool>,
+) -> bool {
+    let key = (a.len(), b.len());
+    if let Some(result) = memo.get(&key) {
+        return *result;
+    }
+    let result = match (a.first(), b.first()) {
+        (None, None) => true,
+        (Some(PatternToken::Wildcard), _) => wildcard_overlaps(b, settings, root, false),
+        (_, Some(PatternToken::Wildcard)) => wildcard_overlaps(a, settings, root, false),
+        (Some(PatternToken::WildcardZero), _) => wildcard_overlaps(b, settings, root, true),
+        (_, Some(PatternToken::WildcardZero)) => wildcard_overlaps(a, settings, root, true),
+        (Some(PatternToken::Star), _) => star_overlaps(a, b, settings, root, memo, false),
+        (_, Some(PatternToken::Star)) => star_overlaps(b, a, settings, root, memo, true),
+        (Some(x), Some(y)) => {
+            if root
+                && ((matches!(x, PatternToken::Any) && y.is_reserved(settings))
+                    || (matches!(y, PatternToken::Any) && x.is_reserved(settings)))
+            {
+                false
             } else {
-                let mut sub = Subscription::default();
-                subscribe_rec(
-                    &mut sub,
-                    sp,
-                    client,
-                    wildcard,
-                    match_any,
-                    formula_prefix,
-                    regex_prefix,
-                );
-                subscription
-                    .subtopics_by_formula
-                    .insert(formula_parsed, sub);
-            }
-        } else if let Some(regex) = regex_prefix.and_then(|p| topic.strip_prefix(p)) {
-            if let Ok(regex) = regex::Regex::new(regex) {
-                let pos = subscription
-                    .subtopics_by_regex
-                    .iter()
-                    .position(|rs| rs.regex.as_str() == regex.as_str());
-                if let Some(pos) = pos {
-                    subscribe_rec(
-                        &mut subscription.subtopics_by_regex[pos].sub,
-                        sp,
-                        client,
-                        wildcard,
-                        match_any,
-                        formula_prefix,
-                        regex_prefix,
-                    );
-                } else {
-                    let mut sub = Subscription::default();
-                    subscribe_rec(
-                        &mut sub,
-                        sp,
-                        client,
-                        wildcard,
-                        match_any,
-                        formula_prefix,
-                        regex_prefix,
-                    );
-                    subscription
-                        .subtopics_by_regex
-                        .push(RegexSubscription { regex, sub });
-                }
+                x.intersects(y, settings)
+                    && patterns_overlap_rec(&a[1..], &b[1..], settings, false, memo)
             }
-        } else if let Some(sub) = subscription.subtopics.get_mut(topic) {
+        }
+        _ => false,
+    };
+    memo.insert(key, result);
+    result
+}
+
+/// Checks if the pattern "a" matches every topic the pattern "b" matches
+fn pattern_covers_rec(
+    a: &[PatternToken],
+    b: &[PatternToken],
+    settings: &Settings,
+    root: bool,
+) -> bool {
+    // wildcards and match-any tokens do not match reserved chunks at the root level
+    let reserved = root && b.first().is_some_and(|t| t.may_be_reserved(settings));
+    match (a.first(), b.first()) {
+        (None, None) => true,
+        (Some(PatternToken::Wildcard), None | Some(PatternToken::WildcardZero)) => {
+            settings.wildcard_matches_parent
+        }
+        (Some(PatternToken::Wildcard | PatternToken::WildcardZero), _) => !reserved,
+        (Some(x), Some(y)) if x.is_single() && y.is_single() => {
+            !(reserved && matches!(x, PatternToken::Any))
+                && x.covers(y, settings)
+                && pattern_covers_rec(&a[1..], &b[1..], settings, false)
+        }
+        // leading wildcards are not analyzed
+        _ => false,
+    }
+}
+
+/// Checks if a terminal wildcard overlaps with the rest of another pattern
+fn wildcard_overlaps(
+    rest: &[PatternToken],
+    settings: &Settings,
+    root: bool,
+    zero_plus: bool,
+) -> bool {
+    match rest.first() {
+        None => zero_plus || settings.wildcard_matches_parent,
+        Some(token) => !(root && token.is_reserved(settings)),
+    }
+}
+
+/// Checks if a pattern, starting with a non-terminal wildcard, overlaps with another one. If
+/// swapped, the star pattern is the second one of the memoized pair
+fn star_overlaps(
+    star: &[PatternToken],
+    other: &[PatternToken],
+    settings: &Settings,
+    root: bool,
+    memo: &mut Map<(usize, usize), bool>,
+    swapped: bool,
+) -> bool {
+    let mut overlap = |star: &[PatternToken], other: &[PatternToken], root: bool| {
+        if swapped {
+            patterns_overlap_rec(other, star, settings, root, memo)
+        } else {
+            patterns_overlap_rec(star, other, settings, root, memo)
+        }
+    };
+    if root && other.first().is_some_and(|t| t.is_reserved(settings)) {
+        return false;
+    }
+    // the wildcard matches no chunks
+    overlap(&star[1..], other, root)
+        // the wildcard takes one more chunk
+        || other
+            .first()
+            .is_some_and(|t| overlap(star, &other[1..], root && !t.is_single()))
+}
+
+fn subscribe_topic<C>(
+    subscriptions: &mut Subscription<C>,
+    shared: &mut Map<String, SharedGroup<C>>,
+    topic: &str,
+    client: &C,
+    settings: &Settings,
+) -> Result<(), Error>
+where
+    C: Client,
+{
+    if let Some((_, filter)) = settings.parse_shared(topic) {
+        if let Some(g) = shared.get_mut(topic) {
             subscribe_rec(
-                sub,
-                sp,
+                &mut g.subscriptions,
+                settings.split(filter),
                 client,
-                wildcard,
-                match_any,
-                formula_prefix,
-                regex_prefix,
-            );
+                settings,
+                filter,
+                0,
+            )?;
         } else {
-            let mut sub = Subscription::default();
+            let mut g = SharedGroup::default();
             subscribe_rec(
-                &mut sub,
-                sp,
+                &mut g.subscriptions,
+                settings.split(filter),
                 client,
-                wildcard,
-                match_any,
-                formula_prefix,
-                regex_prefix,
-            );
-            subscription.subtopics.insert(topic.to_owned(), sub);
+                settings,
+                filter,
+                0,
+            )?;
+            shared.insert(topic.to_owned(), g);
         }
+        Ok(())
     } else {
-        subscription.subscribers.insert(client.clone());
+        subscribe_rec(
+            subscriptions,
+            settings.split(topic),
+            client,
+            settings,
+            topic,
+            0,
+        )
     }
 }
 
-fn unsubscribe_rec<C>(
-    subscription: &mut Subscription<C>,
-    mut sp: Split<char>,
+fn unsubscribe_topic<C>(
+    subscriptions: &mut Subscription<C>,
+    shared: &mut Map<String, SharedGroup<C>>,
+    topic: &str,
     client: &C,
-    wildcard: &Set<String>,
-    match_any: &Set<String>,
-    formula_prefix: Option<&str>,
-    regex_prefix: Option<&str>,
+    settings: &Settings,
 ) where
     C: Client,
 {
-    if let Some(topic) = sp.next() {
-        if wildcard.contains(topic) {
-            subscription.sub_any.remove(client);
-        } else if match_any.contains(topic) {
-            if let Some(ref mut sub) = subscription.subtopics_any {
-                unsubscribe_rec(
-                    sub,
-                    sp,
-                    client,
-                    wildcard,
-                    match_any,
-                    formula_prefix,
-                    regex_prefix,
-                );
-                if sub.is_empty() {
-                    subscription.subtopics_any = None;
-                }
+    if let Some((_, filter)) = settings.parse_shared(topic) {
+        if let Some(g) = shared.get_mut(topic) {
+            unsubscribe_rec(
+                &mut g.subscriptions,
+                settings.split(filter),
+                client,
+                settings,
+            );
+            if g.subscriptions.is_empty() {
+                shared.remove_key(topic);
             }
-        } else if let Some(formula) = formula_prefix.and_then(|p| topic.strip_prefix(p)) {
-            let Ok(formula_parsed) = formula.parse::<Formula>() else {
-                return;
-            };
-            if let Some(sub) = subscription.subtopics_by_formula.get_mut(&formula_parsed) {
-                unsubscribe_rec(
-                    sub,
-                    sp,
-                    client,
-                    wildcard,
-                    match_any,
-                    formula_prefix,
-                    regex_prefix,
-                );
+        }
+    } else {
+        unsubscribe_rec(subscriptions, settings.split(topic), client, settings);
+    }
+}
+
+fn topic_client_set<'a, C>(
+    subscriptions: &'a Subscription<C>,
+    shared: &'a Map<String, SharedGroup<C>>,
+    topic: &str,
+    settings: &Settings,
+) -> Option<&'a Set<C>> {
+    if let Some((_, filter)) = settings.parse_shared(topic) {
+        client_set(
+            &shared.get(topic)?.subscriptions,
+            settings.split(filter),
+            settings,
+        )
+    } else {
+        client_set(subscriptions, settings.split(topic), settings)
+    }
+}
+
+fn topic_client_set_mut<'a, C>(
+    subscriptions: &'a mut Subscription<C>,
+    shared: &'a mut Map<String, SharedGroup<C>>,
+    topic: &str,
+    settings: &Settings,
+) -> Option<&'a mut Set<C>> {
+    if let Some((_, filter)) = settings.parse_shared(topic) {
+        client_set_mut(
+            &mut shared.get_mut(topic)?.subscriptions,
+            settings.split(filter),
+            settings,
+        )
+    } else {
+        client_set_mut(subscriptions, settings.split(topic), settings)
+    }
+}
+
+/// Inserts the client into the subscription tree. Formulas and regular expressions are parsed
+/// and the pattern is validated in the same pass, a branch is attached to the tree only if the
+/// rest of the pattern is valid, so the tree is not changed on errors
+fn subscribe_rec<C>(
+    subscription: &mut Subscription<C>,
+    mut sp: TopicSplit,
+    client: &C,
+    settings: &Settings,
+    pattern: &str,
+    depth: usize,
+) -> Result<(), Error>
+where
+    C: Client,
+{
+    let Some(topic) = sp.next() else {
+        subscription.subscribers.insert(client.clone());
+        return Ok(());
+    };
+    if depth == settings.max_depth {
+        return Err(Error::TopicTooDeep(settings.max_depth));
+    }
+    if settings.wildcard.contains(topic) {
+        if settings.leading_wildcard && sp.clone().next().is_some() {
+            if let Some(sub) = subscription.subtopics_wildcard.as_mut() {
+                subscribe_rec(sub, sp, client, settings, pattern, depth + 1)?;
+            } else {
+                let mut sub = Subscription::default();
+                subscribe_rec(&mut sub, sp, client, settings, pattern, depth + 1)?;
+                subscription.subtopics_wildcard = Some(Box::new(sub));
+            }
+            return Ok(());
+        }
+        if settings.terminal_wildcard && sp.next().is_some() {
+            return Err(Error::WildcardPosition(pattern.to_owned()));
+        }
+        subscription.sub_any.insert(client.clone());
+    } else if settings.wildcard_zero_plus.contains(topic) {
+        if settings.terminal_wildcard && sp.next().is_some() {
+            return Err(Error::WildcardPosition(pattern.to_owned()));
+        }
+        subscription.sub_any_zero.insert(client.clone());
+    } else if settings.match_any.contains(topic) {
+        if let Some(sub) = subscription.subtopics_any.as_mut() {
+            subscribe_rec(sub, sp, client, settings, pattern, depth + 1)?;
+        } else {
+            let mut sub = Subscription::default();
+            subscribe_rec(&mut sub, sp, client, settings, pattern, depth + 1)?;
+            subscription.subtopics_any = Some(Box::new(sub));
+        }
+    } else if let Some(&levels) = settings.match_levels.get(topic) {
+        if depth + levels > settings.max_depth {
+            return Err(Error::TopicTooDeep(settings.max_depth));
+        }
+        if let Some(sub) = subscription.subtopics_levels.get_mut(&levels) {
+            subscribe_rec(sub, sp, client, settings, pattern, depth + levels)?;
+        } else {
+            let mut sub = Subscription::default();
+            subscribe_rec(&mut sub, sp, client, settings, pattern, depth + levels)?;
+            subscription.subtopics_levels.insert(levels, sub);
+        }
+    } else if let Some(formula) = settings
+        .formula_prefix
+        .as_deref()
+        .and_then(|p| topic.strip_prefix(p))
+    {
+        let formula_parsed = formula.parse::<Formula>()?;
+        if let Some(sub) = subscription.subtopics_by_formula.get_mut(&formula_parsed) {
+            subscribe_rec(sub, sp, client, settings, pattern, depth + 1)?;
+        } else {
+            let mut sub = Subscription::default();
+            subscribe_rec(&mut sub, sp, client, settings, pattern, depth + 1)?;
+            subscription
+                .subtopics_by_formula
+                .insert(formula_parsed, sub);
+        }
+    } else if let Some(regex) = settings
+        .regex_prefix
+        .as_deref()
+        .and_then(|p| topic.strip_prefix(p))
+    {
+        // an existing node has got the expression already compiled
+        if let Some(rs) = subscription
+            .subtopics_by_regex
+            .iter_mut()
+            .find(|rs| rs.regex.as_str() == regex)
+        {
+            subscribe_rec(&mut rs.sub, sp, client, settings, pattern, depth + 1)?;
+        } else {
+            let regex =
+                regex::Regex::new(regex).map_err(|e| Error::RegexParseError(e.to_string()))?;
+            let mut sub = Subscription::default();
+            subscribe_rec(&mut sub, sp, client, settings, pattern, depth + 1)?;
+            subscription
+                .subtopics_by_regex
+                .push(RegexSubscription { regex, sub });
+        }
+    } else {
+        let topic = settings.chunk(topic);
+        if let Some(sub) = subscription.subtopics.get_mut(&*topic) {
+            subscribe_rec(sub, sp, client, settings, pattern, depth + 1)?;
+        } else {
+            let mut sub = Subscription::default();
+            subscribe_rec(&mut sub, sp, client, settings, pattern, depth + 1)?;
+            subscription.subtopics.insert(topic.into_owned(), sub);
+        }
+    }
+    Ok(())
+}
+
+/// Finds the set, where clients, subscribed to the topic, are stored
+fn client_set<'a, C>(
+    subscription: &'a Subscription<C>,
+    mut sp: TopicSplit,
+    settings: &Settings,
+) -> Option<&'a Set<C>> {
+    let Some(topic) = sp.next() else {
+        return Some(&subscription.subscribers);
+    };
+    if settings.wildcard.contains(topic) {
+        if settings.leading_wildcard && sp.clone().next().is_some() {
+            client_set(subscription.subtopics_wildcard.as_deref()?, sp, settings)
+        } else {
+            Some(&subscription.sub_any)
+        }
+    } else if settings.wildcard_zero_plus.contains(topic) {
+        Some(&subscription.sub_any_zero)
+    } else if settings.match_any.contains(topic) {
+        client_set(subscription.subtopics_any.as_deref()?, sp, settings)
+    } else if let Some(levels) = settings.match_levels.get(topic) {
+        client_set(subscription.subtopics_levels.get(levels)?, sp, settings)
+    } else if let Some(formula) = settings
+        .formula_prefix
+        .as_deref()
+        .and_then(|p| topic.strip_prefix(p))
+    {
+        let formula_parsed = formula.parse::<Formula>().ok()?;
+        client_set(
+            subscription.subtopics_by_formula.get(&formula_parsed)?,
+            sp,
+            settings,
+        )
+    } else if let Some(regex) = settings
+        .regex_prefix
+        .as_deref()
+        .and_then(|p| topic.strip_prefix(p))
+    {
+        let rs = subscription
+            .subtopics_by_regex
+            .iter()
+            .find(|rs| rs.regex.as_str() == regex)?;
+        client_set(&rs.sub, sp, settings)
+    } else {
+        client_set(
+            subscription.subtopics.get(&*settings.chunk(topic))?,
+            sp,
+            settings,
+        )
+    }
+}
+
+/// Same as client_set, returns the set for modifications
+fn client_set_mut<'a, C>(
+    subscription: &'a mut Subscription<C>,
+    mut sp: TopicSplit,
+    settings: &Settings,
+) -> Option<&'a mut Set<C>> {
+    let Some(topic) = sp.next() else {
+        return Some(&mut subscription.subscribers);
+    };
+    if settings.wildcard.contains(topic) {
+        if settings.leading_wildcard && sp.clone().next().is_some() {
+            client_set_mut(
+                subscription.subtopics_wildcard.as_deref_mut()?,
+                sp,
+                settings,
+            )
+        } else {
+            Some(&mut subscription.sub_any)
+        }
+    } else if settings.wildcard_zero_plus.contains(topic) {
+        Some(&mut subscription.sub_any_zero)
+    } else if settings.match_any.contains(topic) {
+        client_set_mut(subscription.subtopics_any.as_deref_mut()?, sp, settings)
+    } else if let Some(levels) = settings.match_levels.get(topic) {
+        client_set_mut(subscription.subtopics_levels.get_mut(levels)?, sp, settings)
+    } else if let Some(formula) = settings
+        .formula_prefix
+        .as_deref()
+        .and_then(|p| topic.strip_prefix(p))
+    {
+        let formula_parsed = formula.parse::<Formula>().ok()?;
+        client_set_mut(
+            subscription.subtopics_by_formula.get_mut(&formula_parsed)?,
+            sp,
+            settings,
+        )
+    } else if let Some(regex) = settings
+        .regex_prefix
+        .as_deref()
+        .and_then(|p| topic.strip_prefix(p))
+    {
+        let rs = subscription
+            .subtopics_by_regex
+            .iter_mut()
+            .find(|rs| rs.regex.as_str() == regex)?;
+        client_set_mut(&mut rs.sub, sp, settings)
+    } else {
+        client_set_mut(
+            subscription.subtopics.get_mut(&*settings.chunk(topic))?,
+            sp,
+            settings,
+        )
+    }
+}
+
+fn unsubscribe_rec<C>(
+    subscription: &mut Subscription<C>,
+    mut sp: TopicSplit,
+    client: &C,
+    settings: &Settings,
+) where
+    C: Client,
+{
+    if let Some(topic) = sp.next() {
+        if settings.wildcard.contains(topic) {
+            if settings.leading_wildcard && sp.clone().next().is_some() {
+                if let Some(ref mut sub) = subscription.subtopics_wildcard {
+                    unsubscribe_rec(sub, sp, client, settings);
+                    if sub.is_empty() {
+                        subscription.subtopics_wildcard = None;
+                    }
+                }
+            } else {
+                subscription.sub_any.remove_key(client);
+            }
+        } else if settings.wildcard_zero_plus.contains(topic) {
+            subscription.sub_any_zero.remove_key(client);
+        } else if settings.match_any.contains(topic) {
+            if let Some(ref mut sub) = subscription.subtopics_any {
+                unsubscribe_rec(sub, sp, client, settings);
+                if sub.is_empty() {
+                    subscription.subtopics_any = None;
+                }
+            }
+        } else if let Some(levels) = settings.match_levels.get(topic) {
+            if let Some(sub) = subscription.subtopics_levels.get_mut(levels) {
+                unsubscribe_rec(sub, sp, client, settings);
+                if sub.is_empty() {
+                    subscription.subtopics_levels.remove_key(levels);
+                }
+            }
+        } else if let Some(formula) = settings
+            .formula_prefix
+            .as_deref()
+            .and_then(|p| topic.strip_prefix(p))
+        {
+            let Ok(formula_parsed) = formula.parse::<Formula>() else {
+                return;
+            };
+            if let Some(sub) = subscription.subtopics_by_formula.get_mut(&formula_parsed) {
+                unsubscribe_rec(sub, sp, client, settings);
                 if sub.is_empty() {
-                    subscription.subtopics_by_formula.remove(&formula_parsed);
+                    subscription
+                        .subtopics_by_formula
+                        .remove_key(&formula_parsed);
                 }
             }
-        } else if let Some(regex) = regex_prefix.and_then(|p| topic.strip_prefix(p)) {
+        } else if let Some(regex) = settings
+            .regex_prefix
+            .as_deref()
+            .and_then(|p| topic.strip_prefix(p))
+        {
             if let Ok(regex) = regex::Regex::new(regex) {
                 let pos = subscription
                     .subtopics_by_regex
@@ -432,153 +2345,432 @@ fn unsubscribe_rec<C>(
                     .position(|rs| rs.regex.as_str() == regex.as_str());
                 if let Some(pos) = pos {
                     let sub = &mut subscription.subtopics_by_regex[pos].sub;
-                    unsubscribe_rec(
-                        sub,
-                        sp,
-                        client,
-                        wildcard,
-                        match_any,
-                        formula_prefix,
-                        regex_prefix,
-                    );
+                    unsubscribe_rec(sub, sp, client, settings);
                     if sub.is_empty() {
                         subscription.subtopics_by_regex.remove(pos);
                     }
                 }
             }
-        } else if let Some(sub) = subscription.subtopics.get_mut(topic) {
-            unsubscribe_rec(
-                sub,
-                sp,
-                client,
-                wildcard,
-                match_any,
-                formula_prefix,
-                regex_prefix,
-            );
-            if sub.is_empty() {
-                subscription.subtopics.remove(topic);
+        } else {
+            let topic = settings.chunk(topic);
+            if let Some(sub) = subscription.subtopics.get_mut(&*topic) {
+                unsubscribe_rec(sub, sp, client, settings);
+                if sub.is_empty() {
+                    subscription.subtopics.remove_key(&*topic);
+                }
             }
         }
     } else {
-        subscription.subscribers.remove(client);
+        subscription.subscribers.remove_key(client);
     }
 }
 
-fn get_subscribers_rec<C>(
-    subscription: &Subscription<C>,
-    mut sp: Split<char>,
-    formula_prefix: Option<&str>,
-    regex_prefix: Option<&str>,
-    result: &mut Set<C>,
-) where
+enum Lookup<'a, C> {
+    Subscribers(&'a Set<C>),
+    Node(&'a Subscription<C>, TopicSplit<'a>),
+}
+
+/// Matches a single topic chunk at the subscription node, passes sets of matching subscribers
+/// and child nodes to look into next to the callback
+fn lookup_step<'a, C, F>(
+    subscription: &'a Subscription<C>,
+    mut sp: TopicSplit<'a>,
+    settings: &Settings,
+    root: bool,
+    f: &mut F,
+) -> ControlFlow<()>
+where
     C: Client,
+    F: FnMut(Lookup<'a, C>) -> ControlFlow<()>,
 {
+    let reserved = root && settings.is_reserved(sp.clone().next());
+    if let Some(sub) = subscription
+        .subtopics_wildcard
+        .as_ref()
+        .filter(|_| !reserved)
+    {
+        let mut sp_wildcard = sp.clone();
+        loop {
+            f(Lookup::Node(sub, sp_wildcard.clone()))?;
+            if sp_wildcard.next().is_none() {
+                break;
+            }
+        }
+    }
     if let Some(topic) = sp.next() {
-        result.extend(subscription.sub_any.clone());
-        if let Some(formula) = formula_prefix.and_then(|p| topic.strip_prefix(p)) {
+        if !reserved {
+            f(Lookup::Subscribers(&subscription.sub_any))?;
+            f(Lookup::Subscribers(&subscription.sub_any_zero))?;
+        }
+        if let Some(formula) = settings
+            .formula_prefix
+            .as_deref()
+            .and_then(|p| topic.strip_prefix(p))
+        {
             for sub in subscription.subtopics.values_match_key_formula(formula) {
-                get_subscribers_rec(sub, sp.clone(), formula_prefix, regex_prefix, result);
+                f(Lookup::Node(sub, sp.clone()))?;
             }
-        } else if let Some(regex) = regex_prefix.and_then(|p| topic.strip_prefix(p)) {
-            if let Ok(regex) = regex::Regex::new(regex) {
+        } else if let Some(regex) = settings
+            .regex_prefix
+            .as_deref()
+            .and_then(|p| topic.strip_prefix(p))
+        {
+            if let Some(regex) = settings.regex_cache.get(regex) {
                 for (name, sub) in &subscription.subtopics {
                     if regex.is_match(name) {
-                        get_subscribers_rec(sub, sp.clone(), formula_prefix, regex_prefix, result);
+                        f(Lookup::Node(sub, sp.clone()))?;
                     }
                 }
             }
-        } else if let Some(sub) = subscription.subtopics.get(topic) {
-            get_subscribers_rec(sub, sp.clone(), formula_prefix, regex_prefix, result);
+        } else if let Some(sub) = subscription.subtopics.get(&*settings.chunk(topic)) {
+            f(Lookup::Node(sub, sp.clone()))?;
         }
-        if !subscription.subtopics_by_formula.is_empty() {
-            for (formula, sub) in &subscription.subtopics_by_formula {
-                if formula.matches(topic) {
-                    get_subscribers_rec(sub, sp.clone(), formula_prefix, regex_prefix, result);
-                }
+        for (formula, sub) in &subscription.subtopics_by_formula {
+            if formula.matches(topic) {
+                f(Lookup::Node(sub, sp.clone()))?;
+            }
+        }
+        for rs in &subscription.subtopics_by_regex {
+            if rs.regex.is_match(topic) {
+                f(Lookup::Node(&rs.sub, sp.clone()))?;
             }
         }
-        if !subscription.subtopics_by_regex.is_empty() {
-            for rs in &subscription.subtopics_by_regex {
-                if rs.regex.is_match(topic) {
-                    get_subscribers_rec(&rs.sub, sp.clone(), formula_prefix, regex_prefix, result);
+        if !reserved {
+            for (levels, sub) in &subscription.subtopics_levels {
+                if let Some(sp_rest) = skip_levels(topic, sp.clone(), *levels, settings) {
+                    f(Lookup::Node(sub, sp_rest))?;
                 }
             }
         }
         if let Some(ref sub) = subscription.subtopics_any {
-            get_subscribers_rec(sub, sp, formula_prefix, regex_prefix, result);
+            let skip_empty = topic.is_empty()
+                && (!settings.match_any_empty
+                    || (settings.strict_levels && sp.clone().next().is_none()));
+            if !reserved && !skip_empty {
+                f(Lookup::Node(sub, sp))?;
+            }
         }
     } else {
-        result.extend(subscription.subscribers.clone());
+        f(Lookup::Subscribers(&subscription.subscribers))?;
+        f(Lookup::Subscribers(&subscription.sub_any_zero))?;
+        if settings.wildcard_matches_parent {
+            f(Lookup::Subscribers(&subscription.sub_any))?;
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+/// Consumes the given number of topic levels, the first one is already taken from the split.
+/// Returns None if there are not enough levels or an empty one can not be matched
+fn skip_levels<'a>(
+    topic: &str,
+    mut sp: TopicSplit<'a>,
+    levels: usize,
+    settings: &Settings,
+) -> Option<TopicSplit<'a>> {
+    let mut chunk = topic;
+    for level in 1..=levels {
+        if level > 1 {
+            chunk = sp.next()?;
+        }
+        let skip_empty = chunk.is_empty()
+            && (!settings.match_any_empty
+                || (settings.strict_levels && sp.clone().next().is_none()));
+        if skip_empty {
+            return None;
+        }
     }
+    Some(sp)
+}
+
+#[cfg(not(feature = "hashmap"))]
+fn dedup_clients<C: Client>(clients: &mut Vec<C>) {
+    clients.sort_unstable();
+    clients.dedup();
+}
+
+#[cfg(feature = "hashmap")]
+fn dedup_clients<C: Client>(clients: &mut Vec<C>) {
+    let mut seen = Set::default();
+    clients.retain(|client| seen.insert(client.clone()));
 }
 
-fn is_subscribed_rec<C>(
-    subscription: &Subscription<C>,
-    formula_prefix: Option<&str>,
-    regex_prefix: Option<&str>,
-    mut sp: Split<char>,
+fn get_subscribers_rec<'a, C, R>(
+    subscription: &'a Subscription<C>,
+    sp: TopicSplit<'a>,
+    settings: &Settings,
+    root: bool,
+    result: &mut R,
+) where
+    C: Client,
+    R: Extend<C>,
+{
+    let _ = lookup_step(subscription, sp, settings, root, &mut |lookup| {
+        match lookup {
+            Lookup::Subscribers(subscribers) => result.extend(subscribers.iter().cloned()),
+            Lookup::Node(sub, sp) => get_subscribers_rec(sub, sp, settings, false, result),
+        }
+        ControlFlow::Continue(())
+    });
+}
+
+/// Passes all client sets, matching the topic, to the callback
+fn matching_sets_rec<'a, C, F>(
+    subscription: &'a Subscription<C>,
+    sp: TopicSplit<'a>,
+    settings: &Settings,
+    root: bool,
+    f: &mut F,
+) where
+    C: Client,
+    F: FnMut(&'a Set<C>),
+{
+    let _ = lookup_step(subscription, sp, settings, root, &mut |lookup| {
+        match lookup {
+            Lookup::Subscribers(subscribers) => f(subscribers),
+            Lookup::Node(sub, sp) => matching_sets_rec(sub, sp, settings, false, f),
+        }
+        ControlFlow::Continue(())
+    });
+}
+
+fn is_subscribed_rec<'a, C, P>(
+    subscription: &'a Subscription<C>,
+    settings: &Settings,
+    root: bool,
+    sp: TopicSplit<'a>,
+    found: &P,
 ) -> bool
 where
-    C: Ord + Eq + Clone,
+    C: Client,
+    P: Fn(&Set<C>) -> bool,
 {
-    if let Some(topic) = sp.next() {
-        if !subscription.sub_any.is_empty() {
-            return true;
+    lookup_step(subscription, sp, settings, root, &mut |lookup| {
+        let found = match lookup {
+            Lookup::Subscribers(subscribers) => found(subscribers),
+            Lookup::Node(sub, sp) => is_subscribed_rec(sub, settings, false, sp, found),
+        };
+        if found {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
         }
-        if let Some(formula) = formula_prefix.and_then(|p| topic.strip_prefix(p)) {
-            for sub in subscription.subtopics.values_match_key_formula(formula) {
-                if is_subscribed_rec(sub, formula_prefix, regex_prefix, sp.clone()) {
-                    return true;
-                }
-            }
-        } else if let Some(regex) = regex_prefix.and_then(|p| topic.strip_prefix(p)) {
-            if let Ok(regex) = regex::Regex::new(regex) {
-                for (name, sub) in &subscription.subtopics {
-                    if regex.is_match(name)
-                        && is_subscribed_rec(sub, formula_prefix, regex_prefix, sp.clone())
-                    {
-                        return true;
+    })
+    .is_break()
+}
+
+/// A lazy iterator over topic subscribers, see [`SubMap::get_subscribers_iter`]
+struct SubscribersIter<'a, C> {
+    settings: &'a Settings,
+    nodes: Vec<(&'a Subscription<C>, TopicSplit<'a>, bool)>,
+    sets: Vec<&'a Set<C>>,
+    current: Option<<&'a Set<C> as IntoIterator>::IntoIter>,
+    visited: Set<&'a C>,
+}
+
+impl<'a, C> Iterator for SubscribersIter<'a, C>
+where
+    C: Client,
+{
+    type Item = &'a C;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(ref mut current) = self.current {
+                for client in current.by_ref() {
+                    if self.visited.insert(client) {
+                        return Some(client);
                     }
                 }
+                self.current = None;
             }
-        } else if let Some(sub) = subscription.subtopics.get(topic) {
-            if is_subscribed_rec(sub, formula_prefix, regex_prefix, sp.clone()) {
-                return true;
+            if let Some(set) = self.sets.pop() {
+                self.current = Some(set.iter());
+                continue;
             }
-        }
-        if !subscription.subtopics_by_formula.is_empty() {
-            for (formula, sub) in &subscription.subtopics_by_formula {
-                if formula.matches(topic)
-                    && is_subscribed_rec(sub, formula_prefix, regex_prefix, sp.clone())
-                {
-                    return true;
+            let (subscription, sp, root) = self.nodes.pop()?;
+            let _ = lookup_step(subscription, sp, self.settings, root, &mut |lookup| {
+                match lookup {
+                    Lookup::Subscribers(subscribers) => {
+                        if !subscribers.is_empty() {
+                            self.sets.push(subscribers);
+                        }
+                    }
+                    Lookup::Node(sub, sp) => self.nodes.push((sub, sp, false)),
                 }
-            }
+                ControlFlow::Continue(())
+            });
         }
-        if !subscription.subtopics_by_regex.is_empty() {
-            for rs in &subscription.subtopics_by_regex {
-                if rs.regex.is_match(topic)
-                    && is_subscribed_rec(&rs.sub, formula_prefix, regex_prefix, sp.clone())
-                {
-                    return true;
-                }
+    }
+}
+
+/// Registers clients and subscribes them to the given topics
+impl<C> Extend<(C, Vec<String>)> for SubMap<C>
+where
+    C: Client,
+{
+    fn extend<I: IntoIterator<Item = (C, Vec<String>)>>(&mut self, iter: I) {
+        for (client, topics) in iter {
+            self.register_client(&client);
+            for topic in topics {
+                self.subscribe(&topic, &client);
             }
         }
-        if let Some(ref sub) = subscription.subtopics_any {
-            if is_subscribed_rec(sub, formula_prefix, regex_prefix, sp) {
-                return true;
+    }
+}
+
+/// Collects a map with the default configuration
+impl<C> FromIterator<(C, Vec<String>)> for SubMap<C>
+where
+    C: Client,
+{
+    fn from_iter<I: IntoIterator<Item = (C, Vec<String>)>>(iter: I) -> Self {
+        let mut smap = Self::new();
+        smap.extend(iter);
+        smap
+    }
+}
+
+#[cfg(feature = "serde")]
+#[allow(clippy::struct_excessive_bools)]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SubMapState<C> {
+    separator: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    separators: Vec<char>,
+    #[serde(default)]
+    escape_char: Option<char>,
+    formula_prefix: Option<String>,
+    regex_prefix: Option<String>,
+    match_any: Vec<String>,
+    #[serde(default)]
+    match_levels: Vec<(String, usize)>,
+    wildcard: Vec<String>,
+    #[serde(default)]
+    wildcard_zero_plus: Vec<String>,
+    match_any_empty: bool,
+    leading_wildcard: bool,
+    wildcard_matches_parent: bool,
+    #[serde(default)]
+    strict_levels: bool,
+    #[serde(default)]
+    terminal_wildcard: bool,
+    #[serde(default)]
+    case_insensitive: bool,
+    reserved_prefix: Option<String>,
+    #[serde(default)]
+    share_prefix: Option<String>,
+    #[serde(default)]
+    max_topics_per_client: usize,
+    #[serde(default = "default_max_depth")]
+    max_depth: usize,
+    clients: Vec<(C, Vec<String>)>,
+}
+
+#[cfg(feature = "serde")]
+fn default_max_depth() -> usize {
+    DEFAULT_MAX_DEPTH
+}
+
+/// The map is serialized as its configuration plus the subscribed topics of each client, the
+/// subscription tree is rebuilt on deserialization
+#[cfg(feature = "serde")]
+impl<C> serde::Serialize for SubMap<C>
+where
+    C: Client + serde::Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let settings = &self.settings;
+        SubMapState {
+            separator: settings.separator.to_string(),
+            separators: if let Separator::Chars(ref chars) = settings.separator {
+                chars.clone()
+            } else {
+                Vec::new()
+            },
+            escape_char: settings.escape_char,
+            formula_prefix: settings.formula_prefix.clone(),
+            regex_prefix: settings.regex_prefix.clone(),
+            match_any: settings.match_any.iter().cloned().collect(),
+            match_levels: settings
+                .match_levels
+                .iter()
+                .map(|(token, levels)| (token.clone(), *levels))
+                .collect(),
+            wildcard: settings.wildcard.iter().cloned().collect(),
+            wildcard_zero_plus: settings.wildcard_zero_plus.iter().cloned().collect(),
+            match_any_empty: settings.match_any_empty,
+            leading_wildcard: settings.leading_wildcard,
+            wildcard_matches_parent: settings.wildcard_matches_parent,
+            strict_levels: settings.strict_levels,
+            terminal_wildcard: settings.terminal_wildcard,
+            case_insensitive: settings.case_insensitive,
+            reserved_prefix: settings.reserved_prefix.clone(),
+            share_prefix: settings.share_prefix.clone(),
+            max_topics_per_client: self.max_topics_per_client,
+            max_depth: settings.max_depth,
+            clients: self
+                .subscribed_topics
+                .iter()
+                .map(|(client, topics)| (client.clone(), topics.values().cloned().collect()))
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, C> serde::Deserialize<'de> for SubMap<C>
+where
+    C: Client + serde::Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let state = SubMapState::<C>::deserialize(deserializer)?;
+        if state.separator.is_empty() {
+            return Err(serde::de::Error::custom("separator can not be empty"));
+        }
+        let mut smap = if state.separators.is_empty() {
+            SubMap::new().separator_str(&state.separator)
+        } else {
+            SubMap::new().separators(&state.separators)
+        };
+        smap.settings.escape_char = state.escape_char;
+        smap.settings.formula_prefix = state.formula_prefix;
+        smap.settings.regex_prefix = state.regex_prefix;
+        smap.settings.match_any = state.match_any.into_iter().collect();
+        smap.settings.match_levels = state.match_levels.into_iter().collect();
+        smap.settings.wildcard = state.wildcard.into_iter().collect();
+        smap.settings.wildcard_zero_plus = state.wildcard_zero_plus.into_iter().collect();
+        smap.settings.match_any_empty = state.match_any_empty;
+        smap.settings.leading_wildcard = state.leading_wildcard;
+        smap.settings.wildcard_matches_parent = state.wildcard_matches_parent;
+        smap.settings.strict_levels = state.strict_levels;
+        smap.settings.terminal_wildcard = state.terminal_wildcard;
+        smap.settings.case_insensitive = state.case_insensitive;
+        smap.settings.reserved_prefix = state.reserved_prefix;
+        smap.settings.share_prefix = state.share_prefix;
+        smap.max_topics_per_client = state.max_topics_per_client;
+        smap.settings.max_depth = state.max_depth;
+        for (client, topics) in state.clients {
+            smap.register_client(&client);
+            for topic in topics {
+                smap.subscribe(&topic, &client);
             }
         }
-    } else if !subscription.subscribers.is_empty() {
-        return true;
+        Ok(smap)
     }
-    false
 }
 
 #[cfg(test)]
 mod test {
     use super::SubMap;
+    use std::ops::ControlFlow;
     #[test]
     fn test_sub() {
         let mut smap: SubMap<String> = SubMap::new().match_any("+").wildcard("#");
@@ -703,4 +2895,1720 @@ mod test {
         smap.unsubscribe("~^test\\d+$/xxx", &client2);
         assert_eq!(smap.get_subscribers("test1/xxx").len(), 0);
     }
+    #[test]
+    fn test_tokens() {
+        let smap: SubMap<String> = SubMap::new()
+            .separator('.')
+            .match_any("+")
+            .wildcard_multiple(&["#", "*"])
+            .formula_prefix("!");
+        let tokens = smap.tokens();
+        assert_eq!(tokens.separator, ".");
+        assert_eq!(tokens.match_any.len(), 1);
+        assert!(tokens.match_any.contains("+"));
+        assert_eq!(tokens.wildcard.len(), 2);
+        assert!(tokens.wildcard.contains("#"));
+        assert!(tokens.wildcard.contains("*"));
+        assert_eq!(tokens.formula_prefix.as_deref(), Some("!"));
+        assert_eq!(tokens.regex_prefix, None);
+        let smap2: SubMap<u32> = SubMap::new()
+            .separator('.')
+            .match_any("+")
+            .wildcard_multiple(&["*", "#"])
+            .formula_prefix("!");
+        assert_eq!(smap2.tokens(), tokens);
+        assert_ne!(SubMap::<u32>::new().tokens(), tokens);
+    }
+    #[test]
+    fn test_unregister_clients() {
+        let mut smap: SubMap<u32> = SubMap::new();
+        for client in 1..=3 {
+            smap.register_client(&client);
+            smap.subscribe("unit/tests", &client);
+            smap.subscribe("unit/*", &client);
+        }
+        assert_eq!(smap.unregister_clients(&[1, 3, 4]), 2);
+        assert_eq!(smap.client_count(), 1);
+        assert_eq!(smap.subscription_count(), 2);
+        assert_eq!(smap.list_clients(), [2]);
+        assert_eq!(smap.unregister_clients(&[2, 2]), 1);
+        assert!(smap.is_empty());
+        assert!(smap.subscriptions.is_empty());
+    }
+    #[test]
+    fn test_subtree_wildcard_subscribers() {
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        for client in 1..=4 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("#", &1);
+        smap.subscribe("sensors/#", &2);
+        smap.subscribe("sensors/temp", &3);
+        smap.subscribe("sensors/+/#", &4);
+        let subs = smap.subtree_wildcard_subscribers("sensors");
+        assert_eq!(subs.len(), 1);
+        assert!(subs.contains(&2));
+        let subs = smap.subtree_wildcard_subscribers("");
+        assert_eq!(subs.len(), 1);
+        assert!(subs.contains(&1));
+        assert!(smap.subtree_wildcard_subscribers("sensors/temp").is_empty());
+        assert!(smap.subtree_wildcard_subscribers("unknown").is_empty());
+    }
+    #[test]
+    fn test_unicode() {
+        let mut smap: SubMap<String> = SubMap::new()
+            .separator('・')
+            .match_any("＋")
+            .wildcard("＃")
+            .formula_prefix("式")
+            .regex_prefix("正");
+        let client1 = "клиент1".to_owned();
+        let client2 = "клиент2".to_owned();
+        let client3 = "клиент3".to_owned();
+        smap.register_client(&client1);
+        smap.register_client(&client2);
+        smap.register_client(&client3);
+        assert!(smap.subscribe("センサー・温度・１", &client1));
+        assert!(smap.subscribe("センサー・＋・１", &client2));
+        assert!(smap.subscribe("センサー・＃", &client3));
+        let subs = smap.get_subscribers("センサー・温度・１");
+        assert_eq!(subs.len(), 3);
+        let subs = smap.get_subscribers("センサー・湿度・１");
+        assert_eq!(subs.len(), 2);
+        assert!(subs.contains(&client2));
+        assert!(subs.contains(&client3));
+        let subs = smap.get_subscribers("センサー");
+        assert!(subs.is_empty());
+        // "/" is not a separator for this map
+        assert!(smap.get_subscribers("センサー/温度/１").is_empty());
+        assert!(smap.subscribe("値・式ge(2)", &client1));
+        assert!(smap.subscribe("値・正^é+$", &client2));
+        assert_eq!(smap.get_subscribers("値・3").len(), 1);
+        assert_eq!(smap.get_subscribers("値・1").len(), 0);
+        assert_eq!(smap.get_subscribers("値・éé").len(), 1);
+        assert!(smap.unsubscribe("センサー・温度・１", &client1));
+        assert!(smap.unsubscribe("センサー・＋・１", &client2));
+        assert!(smap.unsubscribe("センサー・＃", &client3));
+        assert!(smap.unsubscribe("値・式ge(2)", &client1));
+        assert!(smap.unsubscribe("値・正^é+$", &client2));
+        assert_eq!(smap.subscription_count(), 0);
+        assert!(smap.subscriptions.is_empty());
+    }
+    #[test]
+    fn test_get_subscribers_into_vec() {
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        for client in 1..=3 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("unit/tests", &1);
+        smap.subscribe("unit/+", &1);
+        smap.subscribe("unit/#", &1);
+        smap.subscribe("unit/+", &2);
+        smap.subscribe("other", &3);
+        let mut out = vec![100, 200];
+        smap.get_subscribers_into_vec("unit/tests", &mut out);
+        assert_eq!(out, [1, 2]);
+        smap.get_subscribers_into_vec("other", &mut out);
+        assert_eq!(out, [3]);
+        smap.get_subscribers_into_vec("none", &mut out);
+        assert!(out.is_empty());
+    }
+    #[test]
+    fn test_match_any_empty() {
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        smap.register_client(&1);
+        smap.subscribe("a/+/b", &1);
+        assert_eq!(smap.get_subscribers("a//b").len(), 1);
+        assert!(smap.is_subscribed("a//b"));
+        assert_eq!(smap.get_subscribers("a/x/b").len(), 1);
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .match_any_empty(false);
+        smap.register_client(&1);
+        smap.subscribe("a/+/b", &1);
+        assert_eq!(smap.get_subscribers("a//b").len(), 0);
+        assert!(!smap.is_subscribed("a//b"));
+        assert_eq!(smap.get_subscribers("a/x/b").len(), 1);
+        assert!(smap.is_subscribed("a/x/b"));
+        // an empty chunk is still matched literally
+        smap.subscribe("a//b", &1);
+        assert_eq!(smap.get_subscribers("a//b").len(), 1);
+    }
+    #[test]
+    fn test_idle_clients() {
+        let mut smap: SubMap<u32> = SubMap::new();
+        for client in 1..=3 {
+            smap.register_client(&client);
+        }
+        let idle = |smap: &SubMap<u32>| {
+            let mut clients = smap.idle_clients();
+            clients.sort_unstable();
+            clients.into_iter().copied().collect::<Vec<u32>>()
+        };
+        assert_eq!(idle(&smap), [1, 2, 3]);
+        smap.subscribe("a/b", &2);
+        assert_eq!(idle(&smap), [1, 3]);
+        smap.unsubscribe("a/b", &2);
+        assert_eq!(idle(&smap), [1, 2, 3]);
+        smap.unregister_client(&1);
+        assert_eq!(idle(&smap), [2, 3]);
+    }
+    #[test]
+    fn test_leading_wildcard() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .leading_wildcard(true);
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe("#/errors", &1);
+        smap.subscribe("a/#/b/+", &2);
+        assert_eq!(smap.get_subscribers("errors").len(), 1);
+        assert_eq!(smap.get_subscribers("x/errors").len(), 1);
+        assert_eq!(smap.get_subscribers("x/y/z/errors").len(), 1);
+        assert!(smap.is_subscribed("x/y/z/errors"));
+        assert_eq!(smap.get_subscribers("x/errors/y").len(), 0);
+        assert!(!smap.is_subscribed("x/errors/y"));
+        assert_eq!(smap.get_subscribers("a/b/x").len(), 1);
+        assert_eq!(smap.get_subscribers("a/x/y/b/z").len(), 1);
+        assert_eq!(smap.get_subscribers("a/x/y/b").len(), 0);
+        assert_eq!(
+            smap.get_subscribers("a/b/errors"),
+            [1, 2].into_iter().collect::<crate::types::Set<u32>>()
+        );
+        // terminal wildcards keep working as usual
+        smap.subscribe("a/#", &1);
+        assert_eq!(smap.get_subscribers("a/x").len(), 1);
+        smap.unsubscribe("#/errors", &1);
+        smap.unsubscribe("a/#/b/+", &2);
+        smap.unsubscribe("a/#", &1);
+        assert!(smap.subscriptions.is_empty());
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        smap.register_client(&1);
+        smap.subscribe("#/errors", &1);
+        assert_eq!(smap.get_subscribers("x/y").len(), 1);
+    }
+    #[test]
+    fn test_mqtt() {
+        fn matches(pattern: &str, topic: &str) -> bool {
+            let mut smap: SubMap<u32> = SubMap::mqtt();
+            smap.register_client(&1);
+            smap.subscribe(pattern, &1);
+            let result = smap.get_subscribers(topic).contains(&1);
+            assert_eq!(smap.is_subscribed(topic), result);
+            result
+        }
+        // multi-level wildcard
+        assert!(matches("sport/tennis/player1/#", "sport/tennis/player1"));
+        assert!(matches(
+            "sport/tennis/player1/#",
+            "sport/tennis/player1/ranking"
+        ));
+        assert!(matches(
+            "sport/tennis/player1/#",
+            "sport/tennis/player1/score/wimbledon"
+        ));
+        assert!(!matches("sport/tennis/player1/#", "sport/tennis/player2"));
+        assert!(matches("sport/#", "sport"));
+        assert!(matches("#", "sport"));
+        assert!(matches("#", "sport/tennis"));
+        assert!(matches("#", "/"));
+        // single-level wildcard
+        assert!(matches("sport/tennis/+", "sport/tennis/player1"));
+        assert!(matches("sport/tennis/+", "sport/tennis/player2"));
+        assert!(!matches("sport/tennis/+", "sport/tennis/player1/ranking"));
+        assert!(!matches("sport/+", "sport"));
+        assert!(matches("sport/+", "sport/"));
+        assert!(matches("+/+", "/finance"));
+        assert!(matches("/+", "/finance"));
+        assert!(!matches("+", "/finance"));
+        assert!(matches("+/tennis/#", "sport/tennis/player1"));
+        assert!(matches("+/tennis/#", "sport/tennis"));
+        // topics starting with $
+        assert!(!matches("#", "$SYS/broker/load"));
+        assert!(!matches("+/broker/load", "$SYS/broker/load"));
+        assert!(!matches("+/#", "$SYS/broker/load"));
+        assert!(matches("$SYS/#", "$SYS/broker/load"));
+        assert!(matches("$SYS/broker/+", "$SYS/broker/load"));
+        assert!(matches("$SYS/+/load", "$SYS/broker/load"));
+        assert!(matches("sport/#", "sport/$tennis"));
+        assert!(matches("sport/+", "sport/$tennis"));
+        // "#" must be the last level
+        let mut smap: SubMap<u32> = SubMap::mqtt();
+        smap.register_client(&1);
+        assert!(!smap.subscribe("sport/#/ranking", &1));
+        assert!(!smap.subscribe("$share/g/#/ranking", &1));
+        assert!(matches!(
+            smap.try_subscribe("sport/#/ranking", &1),
+            Err(crate::Error::WildcardPosition(_))
+        ));
+        assert!(matches!(
+            smap.validate_pattern("sport/#/ranking"),
+            Err(crate::Error::WildcardPosition(_))
+        ));
+        assert!(smap.validate_pattern("sport/+/ranking").is_ok());
+        assert!(smap.list_topics(&1).is_empty());
+        assert!(!matches("sport/#/ranking", "sport/tennis"));
+    }
+    #[test]
+    fn test_reserved_prefix() {
+        // no prefix is reserved by default
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        smap.register_client(&1);
+        smap.subscribe("#", &1);
+        assert_eq!(smap.get_subscribers("$SYS/broker/load").len(), 1);
+        let mut smap: SubMap<u32> = SubMap::mqtt().no_reserved_prefix();
+        smap.register_client(&1);
+        smap.subscribe("#", &1);
+        assert_eq!(smap.get_subscribers("$SYS/broker/load").len(), 1);
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .reserved_prefix("$");
+        for client in 1..=4 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("#", &1);
+        smap.subscribe("$SYS/#", &2);
+        smap.subscribe("+/broker/load", &3);
+        smap.subscribe("$SYS/broker/+", &4);
+        let subs = smap.get_subscribers("$SYS/broker/load");
+        assert_eq!(subs.len(), 2);
+        assert!(subs.contains(&2));
+        assert!(subs.contains(&4));
+        assert!(smap.is_subscribed("$SYS/broker/load"));
+        let subs = smap.get_subscribers("SYS/broker/load");
+        assert_eq!(subs.len(), 2);
+        assert!(subs.contains(&1));
+        assert!(subs.contains(&3));
+        smap.unsubscribe("$SYS/#", &2);
+        smap.unsubscribe("$SYS/broker/+", &4);
+        assert!(!smap.is_subscribed("$SYS/broker/load"));
+        assert_eq!(smap.tokens().reserved_prefix.as_deref(), Some("$"));
+    }
+    #[test]
+    fn test_set_subscriptions() {
+        use super::SubChange;
+        let mut smap: SubMap<u32> = SubMap::new();
+        assert_eq!(smap.set_subscriptions(&1, &["a"]), SubChange::default());
+        smap.register_client(&1);
+        smap.subscribe("a/b", &1);
+        smap.subscribe("a/c", &1);
+        smap.subscribe("a/*", &1);
+        let change = smap.set_subscriptions(&1, &["a/c", "x/y", "x/*", "x/y"]);
+        assert_eq!(
+            change,
+            SubChange {
+                added: 2,
+                removed: 2
+            }
+        );
+        let mut topics = smap.list_topics(&1);
+        topics.sort_unstable();
+        assert_eq!(topics, ["a/c", "x/*", "x/y"]);
+        assert_eq!(smap.subscription_count(), 3);
+        assert!(smap.get_subscribers("a/b").is_empty());
+        assert_eq!(smap.get_subscribers("x/z").len(), 1);
+        assert_eq!(smap.set_subscriptions(&1, &[]).removed, 3);
+        assert_eq!(smap.subscription_count(), 0);
+        assert!(smap.subscriptions.is_empty());
+    }
+    #[test]
+    fn test_get_subscribers_iter() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .formula_prefix("!")
+            .regex_prefix("~");
+        for client in 1..=5 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("unit/tests/1", &1);
+        smap.subscribe("unit/+/1", &1);
+        smap.subscribe("unit/#", &1);
+        smap.subscribe("unit/+/1", &2);
+        smap.subscribe("unit/tests/!ge(1)", &3);
+        smap.subscribe("unit/~^te/1", &4);
+        smap.subscribe("unit/~^te/1", &3);
+        smap.subscribe("other/#", &5);
+        for topic in [
+            "unit/tests/1",
+            "unit/tests/0",
+            "unit",
+            "other/x",
+            "none",
+            "unit/+/1",
+        ] {
+            let mut subs: Vec<u32> = smap.get_subscribers_iter(topic).copied().collect();
+            let len = subs.len();
+            subs.sort_unstable();
+            subs.dedup();
+            assert_eq!(subs.len(), len);
+            let mut expected: Vec<u32> = smap.get_subscribers(topic).into_iter().collect();
+            expected.sort_unstable();
+            assert_eq!(subs, expected);
+        }
+        assert_eq!(smap.get_subscribers_iter("unit/tests/1").count(), 4);
+        assert_eq!(smap.get_subscribers_iter("unit/tests/1").take(2).count(), 2);
+        assert!(smap.get_subscribers_iter("none").next().is_none());
+    }
+    #[test]
+    fn test_get_subscribers_count() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .formula_prefix("!");
+        for client in 1..=3 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("a/b/c", &1);
+        smap.subscribe("a/+/c", &1);
+        smap.subscribe("a/#", &1);
+        smap.subscribe("a/!ge(1)/c", &2);
+        smap.subscribe("a/+/c", &2);
+        smap.subscribe("x/y", &3);
+        assert_eq!(smap.get_subscribers_count("a/b/c"), 2);
+        assert_eq!(smap.get_subscribers_count("a/b"), 1);
+        assert_eq!(smap.get_subscribers_count("a/5/c"), 2);
+        assert_eq!(smap.get_subscribers_count("x/y"), 1);
+        assert_eq!(smap.get_subscribers_count("x/z"), 0);
+    }
+    #[test]
+    fn test_get_subscribers_into() {
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        for client in 1..=3 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("a/b", &1);
+        smap.subscribe("a/+", &2);
+        smap.subscribe("x/#", &3);
+        let mut out = crate::types::Set::default();
+        out.insert(100);
+        smap.get_subscribers_into("a/b", &mut out);
+        assert_eq!(out.len(), 2);
+        assert!(out.contains(&1));
+        assert!(out.contains(&2));
+        smap.get_subscribers_into("x/y", &mut out);
+        assert_eq!(out.len(), 1);
+        assert!(out.contains(&3));
+        smap.get_subscribers_into("none", &mut out);
+        assert!(out.is_empty());
+    }
+    #[test]
+    fn test_regex_cache() {
+        let mut smap: SubMap<u32> = SubMap::new().regex_prefix("~");
+        smap.register_client(&1);
+        smap.subscribe("a/test1", &1);
+        smap.subscribe("a/test2", &1);
+        smap.subscribe("a/other", &1);
+        for _ in 0..2 {
+            assert_eq!(smap.get_subscribers("a/~^test").len(), 1);
+            assert!(smap.is_subscribed("a/~^test"));
+            assert!(!smap.is_subscribed("a/~^none"));
+        }
+        assert_eq!(smap.settings.regex_cache.regexes.lock().unwrap().len(), 2);
+        smap.clear_regex_cache();
+        assert!(smap.settings.regex_cache.regexes.lock().unwrap().is_empty());
+        assert!(smap.is_subscribed("a/~^test"));
+        assert!(!smap.is_subscribed("a/~("));
+        for i in 0..super::REGEX_CACHE_SIZE {
+            smap.is_subscribed(&format!("a/~^test{}", i));
+        }
+        assert_eq!(smap.settings.regex_cache.regexes.lock().unwrap().len(), 1);
+    }
+    #[test]
+    fn test_separator_str() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .separator_str("::")
+            .match_any("+")
+            .wildcard("#")
+            .formula_prefix("!");
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.register_client(&3);
+        smap.subscribe("unit::tests::a/b", &1);
+        smap.subscribe("unit::+::a/b", &2);
+        smap.subscribe("unit::#", &3);
+        smap.subscribe("level::!ge(5)", &1);
+        assert_eq!(smap.get_subscribers("unit::tests::a/b").len(), 3);
+        assert_eq!(smap.get_subscribers("unit::x::a/b").len(), 2);
+        assert_eq!(smap.get_subscribers("unit:x::a/b").len(), 0);
+        assert_eq!(smap.get_subscribers("unit::x").len(), 1);
+        assert_eq!(smap.get_subscribers("level::5").len(), 1);
+        assert_eq!(smap.get_subscribers("level::4").len(), 0);
+        assert_eq!(smap.subtree_wildcard_subscribers("unit").len(), 1);
+        assert_eq!(smap.tokens().separator, "::");
+        smap.unregister_client(&1);
+        smap.unregister_client(&2);
+        smap.unregister_client(&3);
+        assert!(smap.subscriptions.is_empty());
+        let smap: SubMap<u32> = SubMap::new().separator_str(".");
+        assert!(matches!(
+            smap.settings.separator,
+            super::Separator::Char('.')
+        ));
+    }
+    #[test]
+    fn test_clear() {
+        let mut smap: SubMap<u32> = SubMap::new().separator('.').wildcard("#");
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe("a.b", &1);
+        smap.subscribe("a.#", &2);
+        smap.clear();
+        assert!(smap.is_empty());
+        assert_eq!(smap.subscription_count(), 0);
+        assert_eq!(smap.client_count(), 0);
+        assert!(smap.subscriptions.is_empty());
+        assert!(!smap.subscribe("a.b", &1));
+        smap.register_client(&1);
+        assert!(smap.subscribe("a.#", &1));
+        assert_eq!(smap.get_subscribers("a.b").len(), 1);
+        assert_eq!(smap.get_subscribers("a/b").len(), 0);
+    }
+    #[test]
+    fn test_subscribe_many() {
+        let mut smap: SubMap<u32> = SubMap::new();
+        assert_eq!(smap.subscribe_many(&["a/b"], &1), 0);
+        smap.register_client(&1);
+        smap.subscribe("a/b", &1);
+        assert_eq!(smap.subscribe_many(&["a/b", "a/c", "x/*", "a/c"], &1), 2);
+        assert_eq!(smap.subscription_count(), 3);
+        assert_eq!(smap.get_subscribers("a/c").len(), 1);
+        assert_eq!(smap.get_subscribers("x/y").len(), 1);
+    }
+    #[test]
+    fn test_list_topics_owned() {
+        let mut smap: SubMap<u32> = SubMap::new();
+        assert!(smap.list_topics_owned(&1).is_empty());
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe("a/b", &1);
+        smap.subscribe("a/c", &1);
+        for topic in smap.list_topics_owned(&1) {
+            smap.unsubscribe(&topic, &1);
+            smap.subscribe(&topic, &2);
+        }
+        assert!(smap.list_topics(&1).is_empty());
+        let mut topics = smap.list_topics_owned(&2);
+        topics.sort();
+        assert_eq!(topics, ["a/b", "a/c"]);
+    }
+    #[test]
+    fn test_rename_client() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .formula_prefix("!")
+            .regex_prefix("~");
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe_many(&["a/b", "a/+/c", "x/#", "n/!ge(5)", "r/~^t"], &1);
+        smap.subscribe("a/b", &2);
+        assert!(!smap.rename_client(&1, &2));
+        assert!(!smap.rename_client(&3, &4));
+        assert!(smap.rename_client(&1, &3));
+        assert_eq!(smap.subscription_count(), 6);
+        assert_eq!(smap.client_count(), 2);
+        assert!(smap.list_topics(&1).is_empty());
+        assert_eq!(smap.list_topics(&3).len(), 5);
+        for topic in ["a/x/c", "x/y", "n/6", "r/test"] {
+            let subs = smap.get_subscribers(topic);
+            assert_eq!(subs.len(), 1);
+            assert!(subs.contains(&3));
+        }
+        let subs = smap.get_subscribers("a/b");
+        assert_eq!(subs.len(), 2);
+        assert!(subs.contains(&2));
+        assert!(subs.contains(&3));
+        smap.unregister_client(&2);
+        smap.unregister_client(&3);
+        assert!(smap.subscriptions.is_empty());
+    }
+    #[test]
+    fn test_subscribe_new() {
+        let mut smap: SubMap<u32> = SubMap::new();
+        assert_eq!(smap.subscribe_new("a/b", &1), None);
+        smap.register_client(&1);
+        assert_eq!(smap.subscribe_new("a/b", &1), Some(true));
+        assert_eq!(smap.subscribe_new("a/b", &1), Some(false));
+        assert!(smap.subscribe("a/b", &1));
+        assert_eq!(smap.subscription_count(), 1);
+    }
+    #[test]
+    fn test_unsubscribe_matching() {
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe_many(
+            &[
+                "sensors",
+                "sensors/temp",
+                "sensors/#",
+                "sensors2/temp",
+                "x/+",
+            ],
+            &1,
+        );
+        smap.subscribe("sensors/#", &2);
+        assert_eq!(smap.unsubscribe_matching("sensors", &3), 0);
+        assert_eq!(smap.unsubscribe_matching("sensors", &1), 3);
+        assert_eq!(smap.subscription_count(), 3);
+        let mut topics = smap.list_topics(&1);
+        topics.sort_unstable();
+        assert_eq!(topics, ["sensors2/temp", "x/+"]);
+        assert_eq!(smap.get_subscribers("sensors/temp").len(), 1);
+        assert_eq!(smap.unsubscribe_matching("x/+", &1), 1);
+        assert_eq!(smap.unsubscribe_matching("sensors2/temp/x", &1), 0);
+        smap.unregister_client(&1);
+        smap.unregister_client(&2);
+        assert!(smap.subscriptions.is_empty());
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut smap: SubMap<String> = SubMap::mqtt().formula_prefix("!").regex_prefix("~");
+        let client1 = "client1".to_owned();
+        let client2 = "client2".to_owned();
+        let client3 = "client3".to_owned();
+        smap.register_client(&client1);
+        smap.register_client(&client2);
+        smap.register_client(&client3);
+        smap.subscribe_many(&["a/b", "a/#", "n/!ge(5)"], &client1);
+        smap.subscribe_many(&["a/+", "r/~^t", "$SYS/#"], &client2);
+        let serialized = serde_json::to_string(&smap).unwrap();
+        let smap2: SubMap<String> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(smap2.tokens(), smap.tokens());
+        assert_eq!(smap2.subscription_count(), smap.subscription_count());
+        assert_eq!(smap2.client_count(), 3);
+        for topic in ["a/b", "a", "a/c", "n/4", "n/5", "r/test", "$SYS/load", "x"] {
+            assert_eq!(smap2.get_subscribers(topic), smap.get_subscribers(topic));
+        }
+        assert!(serde_json::from_str::<SubMap<String>>(
+            &serialized.replace(r#""separator":"/""#, r#""separator":"""#)
+        )
+        .is_err());
+    }
+    #[test]
+    fn test_iter_subscriptions() {
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.register_client(&3);
+        smap.subscribe_many(&["a/b", "a/+", "x/#"], &1);
+        smap.subscribe_many(&["a/b", "x/#"], &2);
+        let mut pairs: Vec<(u32, &str)> = smap.iter_subscriptions().map(|(c, t)| (*c, t)).collect();
+        pairs.sort_unstable();
+        assert_eq!(
+            pairs,
+            [(1, "a/+"), (1, "a/b"), (1, "x/#"), (2, "a/b"), (2, "x/#")]
+        );
+        assert_eq!(smap.iter_subscriptions().count(), smap.subscription_count());
+    }
+    #[test]
+    fn test_shared() {
+        let mut smap: SubMap<u32> = SubMap::mqtt();
+        for client in 1..=5 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("$share/g1/sensors/+", &1);
+        smap.subscribe("$share/g1/sensors/+", &2);
+        // the same group name with another filter is a different group
+        smap.subscribe("$share/g1/sensors/#", &3);
+        smap.subscribe("$share/g2/sensors/temp", &4);
+        smap.subscribe("sensors/temp", &5);
+        assert_eq!(smap.subscription_count(), 5);
+        assert_eq!(smap.shared.len(), 3);
+        assert_eq!(smap.list_topics(&1), ["$share/g1/sensors/+"]);
+        assert_eq!(smap.get_subscribers("sensors/temp").len(), 5);
+        assert_eq!(smap.get_subscribers_iter("sensors/temp").count(), 5);
+        assert!(smap.is_subscribed("sensors/x"));
+        assert!(smap.get_subscribers("$share/g1/sensors/temp").is_empty());
+        let mut g1 = Vec::new();
+        for _ in 0..4 {
+            let subscribers = smap.get_subscribers_shared("sensors/temp");
+            assert_eq!(subscribers.len(), 4);
+            assert!(subscribers.contains(&3));
+            assert!(subscribers.contains(&4));
+            assert!(subscribers.contains(&5));
+            g1.extend(subscribers.into_iter().filter(|c| *c < 3));
+        }
+        // members are picked in turns
+        assert_ne!(g1[0], g1[1]);
+        assert_eq!(g1[..2], g1[2..]);
+        assert_eq!(
+            smap.get_subscribers_shared("sensors/a/b")
+                .into_iter()
+                .collect::<Vec<u32>>(),
+            [3]
+        );
+        smap.rename_client(&3, &6);
+        assert!(smap.get_subscribers("sensors/a/b").contains(&6));
+        smap.unsubscribe("$share/g1/sensors/+", &1);
+        smap.unsubscribe_all(&2);
+        smap.unregister_client(&6);
+        smap.unsubscribe_all(&4);
+        assert_eq!(
+            smap.get_subscribers("sensors/temp")
+                .into_iter()
+                .collect::<Vec<u32>>(),
+            [5]
+        );
+        assert!(smap.shared.is_empty());
+        let mut smap: SubMap<u32> = SubMap::new();
+        smap.register_client(&1);
+        smap.subscribe("$share/g1/a", &1);
+        assert!(smap.get_subscribers("a").is_empty());
+        assert!(smap.get_subscribers("$share/g1/a").contains(&1));
+    }
+    #[test]
+    fn test_strict_levels() {
+        for strict in [false, true] {
+            let mut smap: SubMap<u32> = SubMap::new().match_any("+").strict_levels(strict);
+            smap.register_client(&1);
+            smap.subscribe("a/+", &1);
+            smap.subscribe("b/+/c", &1);
+            assert!(!smap.is_subscribed("a"));
+            assert!(smap.is_subscribed("a/x"));
+            assert_eq!(smap.is_subscribed("a/"), !strict);
+            assert_eq!(smap.get_subscribers("a/").len(), usize::from(!strict));
+            assert!(smap.is_subscribed("b//c"));
+        }
+    }
+    #[test]
+    fn test_case_insensitive() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .formula_prefix("!")
+            .case_insensitive(true);
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe("sensors/temp", &1);
+        smap.subscribe("Alarms/+/State", &2);
+        smap.subscribe("Units/!U#eq(1)", &2);
+        assert_eq!(smap.list_topics(&2).len(), 2);
+        assert!(smap.list_topics(&2).contains(&"Alarms/+/State"));
+        assert!(smap.get_subscribers("Sensors/Temp").contains(&1));
+        assert!(smap.get_subscribers("SENSORS/temp").contains(&1));
+        assert!(smap.get_subscribers("alarms/x/state").contains(&2));
+        assert!(smap.is_subscribed("units/U1"));
+        assert!(!smap.is_subscribed("units/u1"));
+        assert!(smap.has_subscription(&2, "Alarms/+/State"));
+        assert_eq!(smap.unsubscribe_matching("ALARMS", &2), 1);
+        smap.unsubscribe("sensors/temp", &1);
+        assert!(smap.get_subscribers("Sensors/Temp").is_empty());
+        let mut smap: SubMap<u32> = SubMap::new();
+        smap.register_client(&1);
+        smap.subscribe("sensors/temp", &1);
+        assert!(smap.get_subscribers("Sensors/Temp").is_empty());
+    }
+    #[test]
+    fn test_for_each_subscriber() {
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        for client in 1..=5 {
+            smap.register_client(&client);
+            smap.subscribe("a/b", &client);
+            smap.subscribe("a/+", &client);
+        }
+        smap.subscribe("#", &1);
+        let mut seen = Vec::new();
+        smap.for_each_subscriber("a/b", |client| {
+            seen.push(*client);
+            ControlFlow::Continue(())
+        });
+        seen.sort_unstable();
+        assert_eq!(seen, [1, 2, 3, 4, 5]);
+        let mut calls = 0;
+        smap.for_each_subscriber("a/b", |_| {
+            calls += 1;
+            if calls == 2 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(calls, 2);
+    }
+    #[test]
+    fn test_patterns_overlap() {
+        let smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .formula_prefix("!")
+            .regex_prefix("~");
+        for (a, b, overlap) in [
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/b", "a/b/c", false),
+            ("#", "+/+", true),
+            ("#", "a", true),
+            ("a/#", "a", false),
+            ("a/#", "a/b/c", true),
+            ("a/#", "b/#", false),
+            ("+", "a/b", false),
+            ("+/b", "a/+", true),
+            ("+/+", "+/+/+", false),
+            ("+/+/#", "a/+", false),
+            ("+/+/#", "a/+/c/d", true),
+            ("n/!ge(5)", "n/!lt(3)", false),
+            ("n/!ge(5)", "n/!le(5)", true),
+            ("n/!ri(1..5)", "n/!ri(5..9)", true),
+            ("n/!ri(1..4)", "n/!ri(5..9)", false),
+            ("n/!gt(5)", "n/6", true),
+            ("n/!gt(5)", "n/5", false),
+            ("n/!ne(1)", "n/!eq(1)", false),
+            ("n/!ne(1)", "n/!eq(2)", true),
+            ("n/!ne(1)", "n/!ne(1)", true),
+            ("n/!a#eq(1)", "n/!b#eq(1)", false),
+            ("n/!ge(x)", "n/#", false),
+            ("r/~^t", "r/test", true),
+            ("r/~^t", "r/best", false),
+            ("r/~^t", "r/!eq(1)", true),
+        ] {
+            assert_eq!(smap.patterns_overlap(a, b), overlap, "{} vs {}", a, b);
+            assert_eq!(smap.patterns_overlap(b, a), overlap, "{} vs {}", b, a);
+        }
+        let smap: SubMap<u32> = SubMap::mqtt();
+        for (a, b, overlap) in [
+            ("a/#", "a", true),
+            ("#", "$SYS/x", false),
+            ("+/x", "$SYS/x", false),
+            ("$SYS/#", "$SYS/x", true),
+        ] {
+            assert_eq!(smap.patterns_overlap(a, b), overlap, "{} vs {}", a, b);
+            assert_eq!(smap.patterns_overlap(b, a), overlap, "{} vs {}", b, a);
+        }
+        let smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .leading_wildcard(true);
+        for (a, b, overlap) in [
+            ("#/x", "a/b/x", true),
+            ("#/x", "x", true),
+            ("#/x", "a/y", false),
+            ("a/#/x", "#/y/x", true),
+            ("#/x", "#/y", false),
+            ("#/x", "+/+/#", true),
+        ] {
+            assert_eq!(smap.patterns_overlap(a, b), overlap, "{} vs {}", a, b);
+            assert_eq!(smap.patterns_overlap(b, a), overlap, "{} vs {}", b, a);
+        }
+    }
+    #[test]
+    fn test_max_topics_per_client() {
+        let mut smap: SubMap<u32> = SubMap::new();
+        smap.register_client(&1);
+        assert_eq!(smap.subscribe_many(&["a", "b", "c"], &1), 3);
+        smap.set_max_topics_per_client(4);
+        assert_eq!(smap.client_topic_count(&1), 3);
+        assert!(smap.subscribe("d", &1));
+        assert!(!smap.subscribe("e", &1));
+        assert!(smap.subscribe("d", &1));
+        assert!(smap.get_subscribers("e").is_empty());
+        assert_eq!(smap.client_topic_count(&1), 4);
+        smap.unsubscribe("a", &1);
+        assert_eq!(smap.subscribe_many(&["e", "f", "g"], &1), 1);
+        assert_eq!(smap.client_topic_count(&1), 4);
+        assert_eq!(smap.subscription_count(), 4);
+        assert_eq!(smap.client_topic_count(&2), 0);
+        smap.set_max_topics_per_client(0);
+        assert!(smap.subscribe("f", &1));
+    }
+    #[test]
+    fn test_escape_char() {
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#").escape_char('\\');
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe(r"a/b\/c/d", &1);
+        smap.subscribe("+/+/+", &2);
+        assert_eq!(smap.get_subscribers(r"a/b\/c/d").len(), 2);
+        assert!(smap.get_subscribers("a/b/c/d").is_empty());
+        assert!(smap.get_subscribers(r"a/b\\/c/d").is_empty());
+        assert!(smap.is_subscribed(r"x/y\\/z"));
+        assert!(smap.patterns_overlap(r"a/b\/c/d", "+/+/+"));
+        assert_eq!(smap.unsubscribe_matching(r"a/b\/c", &1), 1);
+        assert!(smap.subscribe(r"a\/b/#", &1));
+        assert!(smap.get_subscribers(r"a\/b/c").contains(&1));
+        assert!(!smap.get_subscribers("a/b/c").contains(&1));
+        let mut smap: SubMap<u32> = SubMap::new().separator_str("::").escape_char('^');
+        smap.register_client(&1);
+        smap.subscribe("a::b^::c", &1);
+        assert!(smap.is_subscribed("a::b^::c"));
+        assert!(!smap.is_subscribed("a::b::c"));
+        let mut smap: SubMap<u32> = SubMap::mqtt().escape_char('\\');
+        smap.register_client(&1);
+        smap.subscribe(r"$share/g\/1/a", &1);
+        assert!(smap.get_subscribers_shared("a").contains(&1));
+    }
+    #[test]
+    fn test_try_subscribe() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .formula_prefix("!")
+            .regex_prefix("~");
+        smap.register_client(&1);
+        assert!(matches!(
+            smap.try_subscribe("a/!xxx(2)", &1),
+            Err(crate::Error::UnknownFunction(_))
+        ));
+        assert!(matches!(
+            smap.try_subscribe("a/~(x/+", &1),
+            Err(crate::Error::RegexParseError(_))
+        ));
+        assert_eq!(smap.subscription_count(), 0);
+        assert!(smap.list_topics(&1).is_empty());
+        assert!(smap.subscriptions.is_empty());
+        assert!(smap.try_subscribe("a/!ge(2)/~^x", &1).unwrap());
+        assert!(!smap.try_subscribe("a/!ge(2)", &2).unwrap());
+        assert!(smap.is_subscribed("a/3/xyz"));
+        assert_eq!(smap.subscription_count(), 1);
+    }
+    #[test]
+    fn test_invalid_subscribe() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .formula_prefix("!")
+            .regex_prefix("~");
+        smap.register_client(&1);
+        assert!(!smap.subscribe("a/~(x", &1));
+        assert_eq!(smap.subscribe_new("a/!nope(1)/c", &1), None);
+        assert_eq!(smap.subscription_count(), 0);
+        assert!(smap.list_topics(&1).is_empty());
+        assert!(smap.subscriptions.is_empty());
+        assert_eq!(smap.subscribe_many(&["a/b", "a/~(x/c", "a/!ge(1)"], &1), 2);
+        assert_eq!(smap.subscription_count(), 2);
+        assert_eq!(smap.list_topics(&1).len(), 2);
+        assert!(smap.unsubscribe_all(&1));
+        assert_eq!(smap.subscription_count(), 0);
+        assert!(smap.subscriptions.is_empty());
+    }
+    #[cfg(any(feature = "digest", feature = "native-digest"))]
+    #[test]
+    fn test_digest_clients() {
+        use crate::digest::{sha256, Sha256Digest};
+        let mut smap: SubMap<Sha256Digest> = SubMap::new().match_any("+").wildcard("#");
+        let client1 = sha256("client1");
+        let client2 = sha256("client2");
+        smap.register_client(&client1);
+        smap.register_client(&client2);
+        assert!(smap.subscribe("unit/+/t1", &client1));
+        assert!(smap.subscribe("unit/#", &client2));
+        let mut subscribers: Vec<Sha256Digest> =
+            smap.get_subscribers("unit/tests/t1").into_iter().collect();
+        subscribers.sort_unstable();
+        let mut expected = vec![client1, client2];
+        expected.sort_unstable();
+        assert_eq!(subscribers, expected);
+        assert!(smap.unregister_client(&client2));
+        assert_eq!(smap.get_subscribers("unit/tests/t2").len(), 0);
+        assert_eq!(smap.get_subscribers("unit/tests/t1").len(), 1);
+    }
+    #[cfg(feature = "hashmap")]
+    #[test]
+    fn test_hashmap_engine() {
+        assert_eq!(crate::types::ENGINE, "std-hash");
+        let mut smap: SubMap<u32> = SubMap::new().match_any("+").wildcard("#");
+        for client in 1..=3 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("unit/+/t1", &1);
+        smap.subscribe("unit/#", &2);
+        smap.subscribe("unit/tests/t1", &3);
+        smap.subscribe("unit/tests/t1", &1);
+        let mut subscribers = Vec::new();
+        smap.get_subscribers_into_vec("unit/tests/t1", &mut subscribers);
+        subscribers.sort_unstable();
+        assert_eq!(subscribers, [1, 2, 3]);
+        assert_eq!(smap.get_subscribers_count("unit/tests/t2"), 1);
+        assert!(smap.unregister_client(&2));
+        assert!(!smap.is_subscribed("unit/tests/t2"));
+        assert_eq!(smap.subscription_count(), 3);
+    }
+    #[cfg(all(feature = "fxhash", any(feature = "indexmap", feature = "hashmap")))]
+    #[test]
+    fn test_fxhash() {
+        let mut smap: SubMap<[u64; 4]> = SubMap::new().match_any("+").wildcard("#");
+        let client1 = [1, 2, 3, 4];
+        let client2 = [5, 6, 7, 8];
+        smap.register_client(&client1);
+        smap.register_client(&client2);
+        smap.subscribe("unit/+/t1", &client1);
+        smap.subscribe("unit/#", &client2);
+        assert_eq!(smap.get_subscribers("unit/tests/t1").len(), 2);
+        let expected: crate::types::Set<[u64; 4]> = [client2].into_iter().collect();
+        assert_eq!(smap.get_subscribers("unit/tests/t2"), expected);
+    }
+    #[test]
+    fn test_with_capacity() {
+        let mut smap: SubMap<u32> = SubMap::with_capacity(100).match_any("+");
+        #[cfg(any(feature = "indexmap", feature = "hashmap"))]
+        assert!(smap.subscribed_topics.capacity() >= 100);
+        for client in 0..100 {
+            smap.register_client(&client);
+            smap.subscribe("a/+", &client);
+        }
+        assert_eq!(smap.get_subscribers("a/b").len(), 100);
+    }
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut smap: SubMap<u32> = SubMap::mqtt().formula_prefix("!").regex_prefix("~");
+        for client in 0..1000 {
+            smap.register_client(&client);
+            smap.subscribe(&format!("unit/{}/+", client), &client);
+            smap.subscribe("unit/!ge(500)/#", &client);
+            smap.subscribe("$share/g/unit/~^x", &client);
+        }
+        for client in 2..1000 {
+            smap.unregister_client(&client);
+        }
+        smap.shrink_to_fit();
+        assert_eq!(smap.subscription_count(), 6);
+        assert_eq!(smap.get_subscribers("unit/1/x").len(), 1);
+        assert_eq!(smap.get_subscribers("unit/600/x").len(), 2);
+        assert_eq!(smap.get_subscribers("unit/xyz").len(), 2);
+        assert_eq!(smap.get_subscribers_shared("unit/xyz").len(), 1);
+        smap.register_client(&5);
+        assert!(smap.subscribe("unit/5/+", &5));
+        assert_eq!(smap.get_subscribers("unit/5/x").len(), 1);
+    }
+    #[test]
+    fn test_merge() {
+        let mut smap1: SubMap<u32> = SubMap::mqtt();
+        let mut smap2: SubMap<u32> = SubMap::mqtt();
+        for client in 1..=2 {
+            smap1.register_client(&client);
+        }
+        for client in 2..=3 {
+            smap2.register_client(&client);
+        }
+        smap1.subscribe("unit/+/t1", &1);
+        smap1.subscribe("unit/tests/t1", &2);
+        smap2.subscribe("unit/tests/t1", &2);
+        smap2.subscribe("unit/#", &2);
+        smap2.subscribe("$share/g/unit/tests/t1", &3);
+        smap1.merge(smap2).unwrap();
+        assert_eq!(smap1.subscription_count(), 4);
+        let mut topics = smap1.list_topics(&2);
+        topics.sort_unstable();
+        assert_eq!(topics, ["unit/#", "unit/tests/t1"]);
+        let mut subscribers: Vec<u32> =
+            smap1.get_subscribers("unit/tests/t1").into_iter().collect();
+        subscribers.sort_unstable();
+        assert_eq!(subscribers, [1, 2, 3]);
+        assert_eq!(smap1.get_subscribers("unit/x").len(), 1);
+        let smap3: SubMap<u32> = SubMap::mqtt().formula_prefix("!");
+        assert!(matches!(
+            smap1.merge(smap3),
+            Err(crate::Error::ConfigConflict(_))
+        ));
+        assert_eq!(smap1.subscription_count(), 4);
+    }
+    #[test]
+    fn test_is_subscriber() {
+        let mut smap: SubMap<u32> = SubMap::mqtt().formula_prefix("!");
+        for client in 1..=3 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("unit/tests/t1", &1);
+        smap.subscribe("unit/+/t2", &2);
+        smap.subscribe("unit/#", &3);
+        smap.subscribe("sensor/!ge(10)/value", &1);
+        smap.subscribe("$share/g/unit/tests/t3", &1);
+        assert!(smap.is_subscriber("unit/tests/t1", &1));
+        assert!(!smap.is_subscriber("unit/tests/t1", &2));
+        assert!(smap.is_subscriber("unit/tests/t1", &3));
+        assert!(smap.is_subscriber("unit/x/t2", &2));
+        assert!(!smap.is_subscriber("unit/x/t3", &2));
+        assert!(smap.is_subscriber("unit", &3));
+        assert!(smap.is_subscriber("sensor/15/value", &1));
+        assert!(!smap.is_subscriber("sensor/5/value", &1));
+        assert!(smap.is_subscriber("unit/tests/t3", &1));
+        assert!(!smap.is_subscriber("unit/tests/t1", &4));
+    }
+    #[test]
+    fn test_matching_patterns() {
+        let mut smap: SubMap<u32> = SubMap::mqtt().formula_prefix("!");
+        for client in 1..=3 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("unit/tests/t1", &1);
+        smap.subscribe("unit/#", &1);
+        smap.subscribe("unit/+/t2", &1);
+        smap.subscribe("sensor/!ge(10)/value", &2);
+        smap.subscribe("sensor/#", &2);
+        smap.subscribe("$share/g/sensor/+/value", &3);
+        let mut patterns = smap.matching_patterns("unit/tests/t1");
+        patterns.sort();
+        assert_eq!(
+            patterns,
+            [(&1, "unit/#".to_owned()), (&1, "unit/tests/t1".to_owned())]
+        );
+        let mut patterns = smap.matching_patterns("sensor/15/value");
+        patterns.sort();
+        assert_eq!(
+            patterns,
+            [
+                (&2, "sensor/!ge(10)/value".to_owned()),
+                (&2, "sensor/#".to_owned()),
+                (&3, "$share/g/sensor/+/value".to_owned())
+            ]
+        );
+        let mut patterns = smap.matching_patterns("sensor/5/value");
+        patterns.sort();
+        assert_eq!(
+            patterns,
+            [
+                (&2, "sensor/#".to_owned()),
+                (&3, "$share/g/sensor/+/value".to_owned())
+            ]
+        );
+        assert!(smap.matching_patterns("other").is_empty());
+    }
+    #[test]
+    fn test_matching_patterns_same_node() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any_multiple(&["+", "?"])
+            .wildcard("#")
+            .leading_wildcard(true);
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe("a/+/c", &1);
+        smap.subscribe("a/?/c", &1);
+        smap.subscribe("a/+/d", &1);
+        smap.subscribe("#/c", &2);
+        smap.subscribe("a/?/d", &2);
+        let mut patterns = smap.matching_patterns("a/b/c");
+        patterns.sort();
+        assert_eq!(
+            patterns,
+            [
+                (&1, "a/+/c".to_owned()),
+                (&1, "a/?/c".to_owned()),
+                (&2, "#/c".to_owned())
+            ]
+        );
+    }
+    #[test]
+    fn test_max_depth() {
+        let mut smap: SubMap<u32> = SubMap::mqtt().max_depth(10);
+        smap.register_client(&1);
+        let deep = vec!["x"; 100_000].join("/");
+        assert!(!smap.subscribe(&deep, &1));
+        assert!(matches!(
+            smap.try_subscribe(&deep, &1),
+            Err(crate::Error::TopicTooDeep(10))
+        ));
+        assert!(!smap.subscribe(&["x"; 11].join("/"), &1));
+        assert_eq!(smap.subscription_count(), 0);
+        assert!(smap.subscribe(&["x"; 10].join("/"), &1));
+        assert!(smap.subscribe("x/#", &1));
+        assert!(smap.subscribe(&format!("$share/g/{}", ["x"; 10].join("/")), &1));
+        assert_eq!(smap.get_subscribers(&deep).len(), 1);
+        assert!(smap.is_subscribed(&deep));
+        assert!(!smap.patterns_overlap(&deep, "x/#"));
+    }
+    #[test]
+    fn test_client_entry() {
+        let mut smap: SubMap<u32> = SubMap::mqtt().formula_prefix("!");
+        smap.register_client(&1);
+        assert!(smap.client_entry(&2).is_none());
+        {
+            let mut entry = smap.client_entry(&1).unwrap();
+            assert_eq!(entry.client(), &1);
+            assert!(entry.subscribe("unit/+/t1"));
+            assert!(entry.subscribe("unit/+/t1"));
+            assert!(entry.subscribe("$share/g/unit/#"));
+            assert!(entry.subscribe("unit/tests/t2"));
+            assert!(!entry.subscribe("unit/!xxx(1)"));
+            assert!(entry.unsubscribe("unit/tests/t2"));
+            assert!(!entry.unsubscribe("unit/tests/t2"));
+            assert!(entry.has_subscription("unit/+/t1"));
+            let mut topics: Vec<&str> = entry.topics().collect();
+            topics.sort_unstable();
+            assert_eq!(topics, ["$share/g/unit/#", "unit/+/t1"]);
+        }
+        assert_eq!(smap.subscription_count(), 2);
+        assert_eq!(smap.get_subscribers("unit/tests/t1").len(), 1);
+        assert!(smap.get_subscribers("unit/tests/t2").contains(&1));
+        assert!(smap.unsubscribe("$share/g/unit/#", &1));
+        assert!(smap.get_subscribers("unit/tests/t2").is_empty());
+        smap.set_max_topics_per_client(1);
+        let mut entry = smap.client_entry(&1).unwrap();
+        assert!(!entry.subscribe("unit/tests/t3"));
+    }
+    #[test]
+    fn test_separators() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .separators(&['/', '.'])
+            .match_any("+")
+            .wildcard("#");
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe("unit/tests.t1", &1);
+        smap.subscribe("unit.+/t2", &2);
+        smap.subscribe("sensors.#", &2);
+        assert_eq!(smap.get_subscribers("unit/tests/t1").len(), 1);
+        assert_eq!(smap.get_subscribers("unit.tests.t1").len(), 1);
+        assert_eq!(smap.get_subscribers("unit.tests/t2").len(), 1);
+        assert_eq!(smap.get_subscribers("sensors/a.b").len(), 1);
+        assert!(smap.get_subscribers("unit:tests:t1").is_empty());
+        assert!(smap.unsubscribe("unit.+/t2", &2));
+        assert!(smap.get_subscribers("unit/x/t2").is_empty());
+        assert_eq!(smap.tokens().separator, "/.");
+        #[cfg(feature = "serde")]
+        {
+            let serialized = serde_json::to_string(&smap).unwrap();
+            let smap2: SubMap<u32> = serde_json::from_str(&serialized).unwrap();
+            assert_eq!(smap2.get_subscribers("unit.tests.t1").len(), 1);
+            assert_eq!(smap2.get_subscribers("sensors/a").len(), 1);
+        }
+        let smap: SubMap<u32> = SubMap::new().separators(&['.']);
+        assert!(matches!(
+            smap.settings.separator,
+            super::Separator::Char('.')
+        ));
+    }
+    #[test]
+    fn test_wildcard_matches_parent() {
+        for matches_parent in [false, true] {
+            let mut smap: SubMap<u32> = SubMap::new()
+                .match_any("+")
+                .wildcard("#")
+                .wildcard_matches_parent(matches_parent);
+            smap.register_client(&1);
+            smap.subscribe("unit/#", &1);
+            assert_eq!(
+                smap.get_subscribers("unit").len(),
+                usize::from(matches_parent)
+            );
+            assert_eq!(smap.is_subscribed("unit"), matches_parent);
+            assert_eq!(smap.get_subscribers("unit/tests").len(), 1);
+            assert!(smap.get_subscribers("other").is_empty());
+        }
+    }
+    #[test]
+    fn test_config() {
+        let mut smap: SubMap<u32> = SubMap::mqtt().max_depth(16).case_insensitive(true);
+        smap.set_max_topics_per_client(10);
+        let config = smap.config();
+        assert_eq!(config.tokens, smap.tokens());
+        assert_eq!(config.tokens.separator, "/");
+        assert_eq!(config.tokens.share_prefix.as_deref(), Some("$share"));
+        assert!(config.wildcard_matches_parent);
+        assert!(config.case_insensitive);
+        assert!(!config.leading_wildcard);
+        assert_eq!(config.max_depth, 16);
+        assert_eq!(config.max_topics_per_client, 10);
+        assert_eq!(config.engine, crate::types::ENGINE);
+        let config2 = SubMap::<u32>::new().config();
+        assert_ne!(config2, config);
+        assert_eq!(config2.max_depth, 1024);
+        assert!(!config2.wildcard_matches_parent);
+    }
+    #[test]
+    fn test_get_subscribers_multi() {
+        let mut smap: SubMap<u32> = SubMap::mqtt();
+        for client in 1..=5 {
+            smap.register_client(&client);
+        }
+        smap.subscribe("unit/tests/t1", &1);
+        smap.subscribe("unit/+/t2", &2);
+        smap.subscribe("unit/#", &3);
+        smap.subscribe("$share/g/unit/tests/t2", &4);
+        smap.subscribe("other", &5);
+        let topics = ["unit/tests/t1", "unit/tests/t2", "unit/x"];
+        let multi = smap.get_subscribers_multi(&topics);
+        let mut expected = crate::types::Set::default();
+        for topic in topics {
+            expected.extend(smap.get_subscribers(topic));
+        }
+        assert_eq!(multi, expected);
+        assert_eq!(multi.len(), 4);
+        assert!(!multi.contains(&5));
+        assert!(smap.get_subscribers_multi(&[]).is_empty());
+    }
+    #[test]
+    fn test_build() {
+        let conflict =
+            |smap: SubMap<u32>| matches!(smap.build(), Err(crate::Error::ConfigConflict(_)));
+        assert!(SubMap::<u32>::new().build().is_ok());
+        assert!(SubMap::<u32>::mqtt()
+            .formula_prefix("!")
+            .regex_prefix("~")
+            .build()
+            .is_ok());
+        assert!(conflict(SubMap::new().wildcard("*").match_any("*")));
+        assert!(conflict(
+            SubMap::new().wildcard_multiple(&["#", "*"]).match_any("#")
+        ));
+        assert!(conflict(SubMap::new().wildcard("!").formula_prefix("!")));
+        assert!(conflict(SubMap::new().match_any("~x").regex_prefix("~")));
+        assert!(conflict(SubMap::new().separator('*')));
+        assert!(conflict(SubMap::new().separators(&['/', '?'])));
+        assert!(conflict(
+            SubMap::new().separator_str("::").match_any("a::b")
+        ));
+        assert!(conflict(SubMap::new().match_any("")));
+        assert!(conflict(SubMap::new().formula_prefix("/")));
+        assert!(conflict(SubMap::new().formula_prefix("")));
+        assert!(conflict(
+            SubMap::new().formula_prefix("!").regex_prefix("!")
+        ));
+        assert!(conflict(
+            SubMap::new().formula_prefix("!").regex_prefix("!~")
+        ));
+    }
+    #[test]
+    fn test_take_client_topics() {
+        let mut smap: SubMap<u32> = SubMap::mqtt();
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe_many(&["unit/tests/t1", "unit/#", "$share/g/x/+"], &1);
+        smap.subscribe("unit/#", &2);
+        let mut topics = smap.take_client_topics(&1).unwrap();
+        topics.sort();
+        assert_eq!(topics, ["$share/g/x/+", "unit/#", "unit/tests/t1"]);
+        assert_eq!(smap.client_count(), 1);
+        assert_eq!(smap.subscription_count(), 1);
+        assert!(smap.get_subscribers("x/y").is_empty());
+        assert_eq!(smap.get_subscribers("unit/tests/t1").len(), 1);
+        assert!(smap.take_client_topics(&1).is_none());
+        assert_eq!(smap.take_client_topics(&2).unwrap(), ["unit/#"]);
+        assert!(smap.take_client_topics(&3).is_none());
+        assert!(smap.subscriptions.is_empty());
+        assert!(smap.shared.is_empty());
+    }
+    #[test]
+    fn test_has_exact_subscriber() {
+        let mut smap: SubMap<u32> = SubMap::mqtt().formula_prefix("!");
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe("a/+", &1);
+        smap.subscribe("a/#", &1);
+        smap.subscribe("n/!ge(1)", &1);
+        assert!(smap.is_subscribed("a/b"));
+        assert!(!smap.has_exact_subscriber("a/b"));
+        assert!(!smap.has_exact_subscriber("n/5"));
+        assert!(!smap.has_exact_subscriber("a"));
+        smap.subscribe("a/b", &2);
+        assert!(smap.has_exact_subscriber("a/b"));
+        assert!(!smap.has_exact_subscriber("a/c"));
+        assert!(!smap.has_exact_subscriber("a/b/c"));
+        smap.subscribe("$share/g/x/y", &2);
+        assert!(smap.has_exact_subscriber("x/y"));
+        assert!(!smap.has_exact_subscriber("x"));
+        smap.unsubscribe("a/b", &2);
+        assert!(!smap.has_exact_subscriber("a/b"));
+    }
+    #[test]
+    fn test_bytes() {
+        let mut smap: SubMap<u32> = SubMap::mqtt();
+        smap.register_client(&1);
+        smap.register_client(&2);
+        assert!(smap.subscribe_bytes(b"unit/+/t1", &1));
+        assert!(smap.subscribe_bytes("unit/тест/#".as_bytes(), &2));
+        assert!(!smap.subscribe_bytes(b"unit/\xff/t1", &2));
+        assert!(!smap.subscribe_bytes(b"unit/tests/t1", &3));
+        assert_eq!(smap.subscription_count(), 2);
+        assert_eq!(smap.get_subscribers_bytes(b"unit/tests/t1").len(), 1);
+        assert_eq!(
+            smap.get_subscribers_bytes("unit/тест/t1".as_bytes()).len(),
+            2
+        );
+        assert!(smap.get_subscribers_bytes(b"unit/\xff/t1").is_empty());
+    }
+    #[test]
+    fn test_node_count() {
+        let mut smap: SubMap<u32> = SubMap::mqtt().formula_prefix("!").regex_prefix("~");
+        smap.register_client(&1);
+        smap.register_client(&2);
+        assert_eq!(smap.node_count(), 1);
+        smap.subscribe("a/b", &1);
+        assert_eq!(smap.node_count(), 3);
+        smap.subscribe("a/b", &2);
+        assert_eq!(smap.node_count(), 3);
+        smap.subscribe("a/+/c", &1);
+        assert_eq!(smap.node_count(), 5);
+        smap.subscribe("a/!ge(1)", &1);
+        smap.subscribe("a/~^x", &1);
+        assert_eq!(smap.node_count(), 7);
+        // wildcards are stored in the parent node
+        smap.subscribe("a/#", &1);
+        assert_eq!(smap.node_count(), 7);
+        smap.subscribe("$share/g/a", &2);
+        assert_eq!(smap.node_count(), 9);
+        smap.unsubscribe("a/+/c", &1);
+        assert_eq!(smap.node_count(), 7);
+        smap.unregister_client(&1);
+        assert_eq!(smap.node_count(), 5);
+        smap.unregister_client(&2);
+        assert_eq!(smap.node_count(), 1);
+    }
+    #[test]
+    fn test_diff() {
+        let mut smap1: SubMap<u32> = SubMap::mqtt();
+        for client in 1..=3 {
+            smap1.register_client(&client);
+        }
+        smap1.subscribe_many(&["a/b", "a/#"], &1);
+        smap1.subscribe("x/+", &2);
+        smap1.subscribe("y", &3);
+        let mut smap2 = smap1.clone();
+        assert!(smap1.diff(&smap2).is_empty());
+        smap2.unsubscribe("a/b", &1);
+        smap2.subscribe("a/c", &1);
+        smap2.unregister_client(&2);
+        smap2.register_client(&4);
+        smap2.subscribe("z/#", &4);
+        let mut diff = smap1.diff(&smap2);
+        diff.added.sort();
+        diff.removed.sort();
+        assert_eq!(diff.added, [(1, "a/c".to_owned()), (4, "z/#".to_owned())]);
+        assert_eq!(diff.removed, [(1, "a/b".to_owned()), (2, "x/+".to_owned())]);
+        let mut mirror = smap1.clone();
+        mirror.register_client(&4);
+        for (client, topic) in &diff.removed {
+            mirror.unsubscribe(topic, client);
+        }
+        for (client, topic) in &diff.added {
+            mirror.subscribe(topic, client);
+        }
+        assert!(mirror.diff(&smap2).is_empty());
+        assert_eq!(mirror.subscription_count(), smap2.subscription_count());
+    }
+    #[test]
+    fn test_collect() {
+        let smap: SubMap<u32> = vec![
+            (1, vec!["a/b".to_owned(), "a/*".to_owned()]),
+            (2, vec!["a/?".to_owned()]),
+            (3, Vec::new()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(smap.client_count(), 3);
+        assert_eq!(smap.subscription_count(), 3);
+        assert_eq!(smap.get_subscribers("a/b").len(), 2);
+        assert_eq!(smap.get_subscribers("a/b/c").len(), 1);
+        let mut smap2: SubMap<u32> = SubMap::mqtt();
+        smap2.extend([(1, vec!["a/+".to_owned(), "a/+".to_owned()])]);
+        smap2.extend([(1, vec!["a/#".to_owned()]), (2, vec!["b".to_owned()])]);
+        assert_eq!(smap2.client_count(), 2);
+        assert_eq!(smap2.subscription_count(), 3);
+        assert_eq!(smap2.get_subscribers("a").len(), 1);
+    }
+    #[test]
+    fn test_wildcard_zero_plus() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .wildcard_zero_plus("**");
+        smap.register_client(&1);
+        smap.register_client(&2);
+        assert!(smap.subscribe("unit/**", &1));
+        assert!(smap.subscribe("unit/#", &2));
+        let clients = smap.get_subscribers("unit");
+        assert_eq!(clients.len(), 1);
+        assert!(clients.contains(&1));
+        let mut clients: Vec<u32> = smap.get_subscribers("unit/a").into_iter().collect();
+        clients.sort_unstable();
+        assert_eq!(clients, [1, 2]);
+        assert_eq!(smap.get_subscribers("unit/a/b").len(), 2);
+        assert!(smap.get_subscribers("other").is_empty());
+        assert!(smap.is_subscribed("unit"));
+        assert!(smap.patterns_overlap("unit/**", "unit"));
+        assert!(!smap.patterns_overlap("unit/#", "unit"));
+        assert!(smap.patterns_overlap("unit/**", "unit/+/b"));
+        assert!(smap.unsubscribe("unit/**", &1));
+        assert!(smap.get_subscribers("unit").is_empty());
+        assert!(SubMap::<u32>::new()
+            .wildcard("**")
+            .wildcard_zero_plus("**")
+            .build()
+            .is_err());
+    }
+    #[test]
+    fn test_export_import() {
+        let mut smap: SubMap<u32> = SubMap::mqtt();
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.register_client(&3);
+        smap.subscribe("unit/+/t", &1);
+        smap.subscribe("unit/#", &2);
+        smap.subscribe("$share/g/unit/a", &2);
+        smap.subscribe("other", &1);
+        let entries = smap.export();
+        assert_eq!(entries.len(), 4);
+        let mut restored: SubMap<u32> = SubMap::mqtt();
+        restored.import(&entries);
+        for topic in ["unit/a/t", "unit", "unit/a", "other", "x"] {
+            assert_eq!(
+                smap.get_subscribers(topic),
+                restored.get_subscribers(topic),
+                "{}",
+                topic
+            );
+        }
+        assert_eq!(
+            smap.get_subscribers_shared("unit/a"),
+            restored.get_subscribers_shared("unit/a")
+        );
+        assert_eq!(restored.subscription_count(), 4);
+        assert!(restored.diff(&smap).is_empty());
+        assert_eq!(restored.client_count(), 2);
+    }
+    #[test]
+    fn test_topics_under() {
+        let mut smap: SubMap<u32> = SubMap::mqtt();
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe("sensors/temp/1", &1);
+        smap.subscribe("sensors/temp/1", &2);
+        smap.subscribe("sensors/+/2", &1);
+        smap.subscribe("sensors/#", &2);
+        smap.subscribe("sensors", &2);
+        smap.subscribe("sensorsx/a", &2);
+        smap.subscribe("$share/g/sensors/temp/3", &1);
+        smap.subscribe("other/sensors", &1);
+        assert_eq!(
+            smap.topics_under("sensors"),
+            [
+                "$share/g/sensors/temp/3",
+                "sensors",
+                "sensors/#",
+                "sensors/+/2",
+                "sensors/temp/1"
+            ]
+        );
+        assert_eq!(smap.topics_under("sensors/"), smap.topics_under("sensors"));
+        assert_eq!(
+            smap.topics_under("sensors/temp"),
+            ["$share/g/sensors/temp/3", "sensors/temp/1"]
+        );
+        assert_eq!(smap.topics_under("sensors/+"), ["sensors/+/2"]);
+        assert!(smap.topics_under("sensors/temp/1/x").is_empty());
+        assert_eq!(smap.topics_under("").len(), 7);
+    }
+    #[test]
+    fn test_match_levels() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .match_levels("++", 2)
+            .match_levels("+++", 3);
+        smap.register_client(&1);
+        smap.register_client(&2);
+        assert!(smap.subscribe("a/++/b", &1));
+        assert!(smap.subscribe("a/+++", &2));
+        assert_eq!(smap.get_subscribers("a/x/y/b").len(), 2);
+        assert_eq!(smap.get_subscribers("a/x/y/c").len(), 1);
+        assert!(smap.get_subscribers("a/x/y/c").contains(&2));
+        assert!(smap.get_subscribers("a/x/b").is_empty());
+        assert!(smap.get_subscribers("a/x/y/z/b").is_empty());
+        assert!(smap.get_subscribers("a/x/y/z").contains(&2));
+        assert!(smap.get_subscribers("a/x/y").is_empty());
+        assert!(smap.is_subscriber("a/1/2/b", &1));
+        assert!(smap.patterns_overlap("a/++/b", "a/+/+/b"));
+        assert!(!smap.patterns_overlap("a/++/b", "a/+/b"));
+        assert!(smap.patterns_overlap("a/++/b", "a/x/#"));
+        assert_eq!(smap.tokens().match_levels.get("++"), Some(&2));
+        assert!(smap.unsubscribe("a/++/b", &1));
+        assert!(!smap.get_subscribers("a/x/y/b").contains(&1));
+        assert!(smap.unsubscribe("a/+++", &2));
+        assert!(smap.subscriptions.is_empty());
+        assert!(SubMap::<u32>::new()
+            .match_any("+")
+            .match_levels("+", 2)
+            .build()
+            .is_err());
+    }
+    #[test]
+    fn test_get_subscribers_if_any() {
+        let mut smap: SubMap<u32> = SubMap::mqtt();
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.subscribe("unit/+", &1);
+        smap.subscribe("unit/#", &2);
+        assert!(smap.get_subscribers_if_any("other").is_none());
+        let subscribers = smap.get_subscribers_if_any("unit/a").unwrap();
+        assert_eq!(subscribers.len(), 2);
+        assert!(subscribers.contains(&1) && subscribers.contains(&2));
+        let subscribers = smap.get_subscribers_if_any("unit/a/b").unwrap();
+        assert_eq!(subscribers.len(), 1);
+        assert!(subscribers.contains(&2));
+    }
+    #[test]
+    fn test_drain() {
+        let mut smap: SubMap<u32> = SubMap::mqtt();
+        smap.register_client(&1);
+        smap.register_client(&2);
+        smap.register_client(&3);
+        smap.subscribe("unit/+", &1);
+        smap.subscribe("unit/#", &2);
+        smap.subscribe("$share/g/unit/a", &2);
+        let mut drained = smap.drain();
+        drained.sort();
+        assert_eq!(
+            drained,
+            [
+                (1, "unit/+".to_owned()),
+                (2, "$share/g/unit/a".to_owned()),
+                (2, "unit/#".to_owned())
+            ]
+        );
+        assert!(smap.is_empty());
+        assert_eq!(smap.subscription_count(), 0);
+        assert_eq!(smap.client_count(), 0);
+        assert!(smap.get_subscribers("unit/a").is_empty());
+        assert!(smap.get_subscribers_shared("unit/a").is_empty());
+        assert!(smap.drain().is_empty());
+    }
+    #[test]
+    fn test_validate_pattern() {
+        let smap: SubMap<u32> = SubMap::mqtt()
+            .formula_prefix("!")
+            .regex_prefix("~")
+            .max_depth(4);
+        for pattern in [
+            "a/b",
+            "a/+/#",
+            "a/!ge(2)/~^x",
+            "$share/g/a/!eq(1)",
+            "a/b/c/d",
+        ] {
+            assert!(smap.validate_pattern(pattern).is_ok(), "{}", pattern);
+        }
+        assert!(matches!(
+            smap.validate_pattern("a/!xx(1)"),
+            Err(crate::Error::UnknownFunction(_))
+        ));
+        assert!(matches!(
+            smap.validate_pattern("$share/g/a/!ge(x)"),
+            Err(crate::Error::ValueParse { .. })
+        ));
+        assert!(matches!(
+            smap.validate_pattern("a/~(x"),
+            Err(crate::Error::RegexParseError(_))
+        ));
+        assert!(matches!(
+            smap.validate_pattern("a/b/c/d/e"),
+            Err(crate::Error::TopicTooDeep(4))
+        ));
+        assert!(smap.is_empty());
+    }
+    #[test]
+    fn test_redundant_topics() {
+        let mut smap: SubMap<u32> = SubMap::mqtt().formula_prefix("!");
+        smap.register_client(&1);
+        for topic in [
+            "a/#",
+            "a/b/c",
+            "a/+",
+            "a",
+            "x/y",
+            "x/+/z",
+            "x/!ge(2)/z",
+            "x/5/z",
+            "$share/g/a/b",
+        ] {
+            smap.subscribe(topic, &1);
+        }
+        assert_eq!(
+            smap.redundant_topics(&1),
+            ["a", "a/+", "a/b/c", "x/!ge(2)/z", "x/5/z"]
+        );
+        smap.register_client(&2);
+        smap.subscribe("#", &2);
+        smap.subscribe("$SYS/x", &2);
+        smap.subscribe("b/!ge(1)", &2);
+        smap.subscribe("b/3", &2);
+        assert_eq!(smap.redundant_topics(&2), ["b/!ge(1)", "b/3"]);
+        assert!(smap.redundant_topics(&3).is_empty());
+        assert_eq!(smap.compact_client(&1), 5);
+        let mut topics = smap.list_topics(&1);
+        topics.sort_unstable();
+        assert_eq!(topics, ["$share/g/a/b", "a/#", "x/+/z", "x/y"]);
+        assert!(smap.redundant_topics(&1).is_empty());
+        assert!(smap.get_subscribers("a/b/c").contains(&1));
+        assert!(smap.get_subscribers("x/5/z").contains(&1));
+    }
+    #[test]
+    fn test_redundant_topics_equivalent() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .wildcard_zero_plus("**")
+            .wildcard_matches_parent(true);
+        smap.register_client(&1);
+        smap.subscribe("a/#", &1);
+        smap.subscribe("a/**", &1);
+        assert_eq!(smap.redundant_topics(&1), ["a/**"]);
+        assert_eq!(smap.compact_client(&1), 1);
+        assert_eq!(smap.list_topics(&1), ["a/#"]);
+    }
+    #[test]
+    fn test_case_insensitive_spellings() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .case_insensitive(true);
+        smap.register_client(&1);
+        assert_eq!(smap.subscribe_new("Sensors/Temp", &1), Some(true));
+        assert_eq!(smap.subscribe_new("sensors/temp", &1), Some(false));
+        assert_eq!(smap.subscription_count(), 1);
+        // the original spelling is kept
+        assert_eq!(smap.list_topics(&1), ["Sensors/Temp"]);
+        assert!(smap.has_subscription(&1, "SENSORS/TEMP"));
+        assert!(smap.unsubscribe("sensors/temp", &1));
+        assert_eq!(smap.subscription_count(), 0);
+        assert!(smap.list_topics(&1).is_empty());
+        assert!(smap.get_subscribers("sensors/temp").is_empty());
+        smap.subscribe("Sensors/+", &1);
+        assert!(smap.get_subscribers("sensors/temp").contains(&1));
+        assert_eq!(
+            smap.set_subscriptions(&1, &["sensors/+", "Alarms/#"]),
+            super::SubChange {
+                added: 1,
+                removed: 0
+            }
+        );
+        assert_eq!(smap.subscription_count(), 2);
+    }
+    #[test]
+    fn test_separators_spellings() {
+        let mut smap: SubMap<u32> = SubMap::new()
+            .separators(&['/', '.'])
+            .match_any("+")
+            .wildcard("#")
+            .share_prefix("$share");
+        smap.register_client(&1);
+        assert!(smap.subscribe("a/b", &1));
+        assert_eq!(smap.subscribe_new("a.b", &1), Some(false));
+        assert!(smap.subscribe("$share/g/x.+", &1));
+        assert_eq!(smap.subscribe_new("$share.g.x/+", &1), Some(false));
+        assert_eq!(smap.subscription_count(), 2);
+        assert!(smap.unsubscribe("a.b", &1));
+        assert!(smap.get_subscribers("a/b").is_empty());
+        assert!(smap.unsubscribe("$share/g/x/+", &1));
+        assert!(smap.get_subscribers("x/y").is_empty());
+        assert_eq!(smap.subscription_count(), 0);
+        assert!(smap.list_topics(&1).is_empty());
+    }
+    #[test]
+    fn test_patterns_overlap_deep_wildcards() {
+        let smap: SubMap<u32> = SubMap::new()
+            .match_any("+")
+            .wildcard("#")
+            .leading_wildcard(true);
+        let stars = format!("{}x", "#/".repeat(200));
+        let literals = format!("{}z", "y/".repeat(200));
+        let started = std::time::Instant::now();
+        assert!(!smap.patterns_overlap(&stars, &literals));
+        assert!(!smap.patterns_overlap(&literals, &stars));
+        assert!(smap.patterns_overlap(&stars, &format!("{}x", "y/".repeat(200))));
+        assert!(smap.patterns_overlap(&stars, &format!("{}+/x", "#/".repeat(100))));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
 }